@@ -2,7 +2,7 @@
 #![allow(unexpected_cfgs)]
 
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer, MintTo, mint_to, Burn, transfer, burn};
+use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer, MintTo, mint_to, Burn, transfer, burn, CloseAccount, close_account};
 use pyth_solana_receiver_sdk::price_update::{get_feed_id_from_hex, PriceUpdateV2};
 
 declare_id!("F5SxeR2fW3R23GVCBSicwk45Zn9nhDCgSPHXirm2Vsom");
@@ -10,10 +10,16 @@ declare_id!("F5SxeR2fW3R23GVCBSicwk45Zn9nhDCgSPHXirm2Vsom");
 const PRICE_PRECISION: u64 = 1_000_000; // 6 decimals
 const USD_PRECISION: u64 = 1_000_000; // 6 decimals
 const BPS_PRECISION: u64 = 10_000; //1e4 for basis points
-const MAX_LEVERAGE: u32 = 8000; // 80x max leverage
+const MAX_LEVERAGE: u64 = 800_000; // 80x max leverage, in BPS like all leverage values (10_000 = 1x)
 const LIQUIDATION_THRESHOLD: u64 = 8000; // 80% in basis points
 const MIN_COLLATERAL_SOL: u64 = 10_000_000; // 0.01 SOL minimum (in lamports) 
-const MAX_PRICE_AGE: u64 = 60; // 60 seconds max age for price
+const MAX_PRICE_AGE: u64 = 60; // default max age for price, configurable per custody
+const MAX_ADMINS: usize = 5; // matches Perpetuals::admins max_len
+const MAX_POSITIONS: usize = 16; // matches PositionRegistry::positions max_len
+const RATE_PRECISION: u64 = 1_000_000; // 1e6 for rates and utilization
+const SECONDS_PER_YEAR: u64 = 31_536_000; // borrow rates are annualized
+const MAX_BORROW_RATE: u64 = 10 * RATE_PRECISION; // 1000% a year, cap on each borrow rate param
+const MAX_OPEN_INTEREST_USD: u64 = 1_000_000_000_000 * USD_PRECISION; // $1T cap on max_global_*_size_usd
 
 #[program]
 pub mod perpetuals {
@@ -21,6 +27,13 @@ pub mod perpetuals {
 
     //admin instructions
     pub fn initialize(ctx: Context<Initialize>, min_signatures: u8, admins: Vec<Pubkey>) -> Result<()> {
+        validate_admin_signers(&admins, min_signatures)?;
+
+        let multisig = &mut ctx.accounts.multisig;
+        multisig.instruction_hash = [0; 32];
+        multisig.signers = Vec::new();
+        multisig.bump = ctx.bumps.multisig;
+
         let perpetuals = &mut ctx.accounts.perpetuals;
         perpetuals.permissions = Permissions {
             allow_swap: true,
@@ -33,22 +46,75 @@ pub mod perpetuals {
             allow_size_change: true,
         };
         perpetuals.pools = Vec::new();
-        perpetuals.admin_authority = ctx.accounts.admin.key();
         perpetuals.min_signatures = min_signatures;
         perpetuals.admins = admins;
+        perpetuals.fee_receiver = ctx.accounts.admin.key();
         perpetuals.bump = ctx.bumps.perpetuals;
 
         Ok(())
     }
 
+    //admin instructions
+    pub fn set_admin_signers(ctx: Context<SetAdminSigners>, admins: Vec<Pubkey>, min_signatures: u8) -> Result<()> {
+        validate_admin_signers(&admins, min_signatures)?;
+
+        let instruction_hash = get_instruction_hash("set_admin_signers", &[], &(admins.clone(), min_signatures))?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        let perpetuals = &mut ctx.accounts.perpetuals;
+        perpetuals.admins = admins;
+        perpetuals.min_signatures = min_signatures;
+
+        Ok(())
+    }
+
+    //admin instructions
+    pub fn set_fee_receiver(ctx: Context<SetFeeReceiver>, fee_receiver: Pubkey) -> Result<()> {
+        require!(fee_receiver != Pubkey::default(), PerpError::InvalidFeeReceiver);
+
+        let instruction_hash = get_instruction_hash("set_fee_receiver", &[], &fee_receiver)?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        ctx.accounts.perpetuals.fee_receiver = fee_receiver;
+
+        Ok(())
+    }
+
     //admin instructions
     pub fn add_pool(ctx: Context<AddPool>, name: String) -> Result<()> {
         require!(name.len() <= 64, PerpError::InvalidPoolName);
 
+        let instruction_hash = get_instruction_hash("add_pool", &[], &name)?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        require!(!ctx.accounts.perpetuals.pools.contains(&ctx.accounts.pool.key()), PerpError::PoolAlreadyExists);
+
         let pool = &mut ctx.accounts.pool;
         pool.name = name;
         pool.custodies = Vec::new();
         pool.aum_usd = 0;
+        pool.permissions = None;
         pool.bump = ctx.bumps.pool;
         pool.lp_token_bump = ctx.bumps.lp_token_mint;
         pool.inception_time = Clock::get()?.unix_timestamp;
@@ -61,16 +127,43 @@ pub mod perpetuals {
     }
 
     //admin instructions
-    pub fn add_custody(ctx: Context<AddCustody>, is_stable: bool, oracle_type: OracleType, initial_price: u64) -> Result<()> {
+    pub fn add_custody(
+        ctx: Context<AddCustody>,
+        is_stable: bool,
+        oracle: Pubkey,
+        oracle_type: OracleType,
+        feed_id: Option<String>,
+        initial_price: u64,
+    ) -> Result<()> {
         require!(initial_price > 0, PerpError::InvalidPrice);
+        validate_oracle_config(oracle, &oracle_type, &feed_id)?;
+
+        let instruction_hash = get_instruction_hash(
+            "add_custody",
+            &[ctx.accounts.pool.key(), ctx.accounts.custody_token_mint.key()],
+            &(is_stable, oracle, oracle_type.clone(), feed_id.clone(), initial_price)
+        )?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        require!(!ctx.accounts.pool.custodies.contains(&ctx.accounts.custody.key()), PerpError::CustodyAlreadyExists);
 
         let custody = &mut ctx.accounts.custody;
         custody.pool = ctx.accounts.pool.key();
         custody.mint = ctx.accounts.custody_token_mint.key();
         custody.decimals = ctx.accounts.custody_token_mint.decimals;
         custody.is_stable = is_stable;
-        custody.oracle = Pubkey::default();
+        custody.oracle = oracle;
         custody.oracle_type = oracle_type;
+        custody.feed_id = feed_id;
+        custody.trading_halted = false;
+        custody.permissions = None;
         custody.bump = ctx.bumps.custody;
         custody.token_account_bump = ctx.bumps.custody_token_account;
 
@@ -81,9 +174,13 @@ pub mod perpetuals {
             trade_spread_long: 50, // 0.5%
             trade_spread_short: 50, // 0.5%
             swap_spread: 30, // 0.3%
-            max_leverage: 50_000, // 50x leverage
+            max_leverage: 100_000, // 10x leverage, keeps the 80% margin above close + liquidation fees
             max_global_short_size_usd: 10_000_000 * USD_PRECISION, 
             max_global_long_size_usd: 10_000_000 * USD_PRECISION, 
+            max_funding_rate: 100_000, // 10% a year at full skew
+            max_conf_bps: 100, // 1%
+            max_price_age_sec: MAX_PRICE_AGE,
+            max_price_deviation_bps: 1000, // 10%
             current_price: initial_price,
             ema_price: initial_price,
             last_update_time: Clock::get()?.unix_timestamp
@@ -125,11 +222,26 @@ pub mod perpetuals {
             liquidation_usd: 0,
         };
 
+        custody.borrow_rate_state = BorrowRateState {
+            current_rate: 0,
+            cumulative_interest: 0,
+            last_update: Clock::get()?.unix_timestamp,
+        };
+
+        custody.funding_rate_state = FundingRateState {
+            current_rate: 0,
+            cumulative_long: 0,
+            cumulative_short: 0,
+            last_update: Clock::get()?.unix_timestamp,
+        };
+
         custody.trade_stats = TradeStats {
             oi_long_usd: 0,
             oi_short_usd: 0,
             total_long_funding: 0,
-            total_short_funding: 0
+            total_short_funding: 0,
+            long_quantity: 0,
+            short_quantity: 0,
         };
 
         let pool = &mut ctx.accounts.pool;
@@ -138,18 +250,154 @@ pub mod perpetuals {
         Ok(())
     }
 
+    //admin instructions
+    pub fn set_permissions(ctx: Context<SetPermissions>, permissions: Permissions) -> Result<()> {
+        let instruction_hash = get_instruction_hash("set_permissions", &[], &permissions)?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        let perpetuals = &mut ctx.accounts.perpetuals;
+        perpetuals.permissions = permissions;
+
+        Ok(())
+    }
+
+    //admin instructions
+    pub fn set_pool_permissions(ctx: Context<SetPoolPermissions>, permissions: Option<Permissions>) -> Result<()> {
+        let instruction_hash = get_instruction_hash("set_pool_permissions", &[ctx.accounts.pool.key()], &permissions)?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.permissions = permissions;
+
+        Ok(())
+    }
+
+    //admin instructions
+    pub fn set_custody_permissions(ctx: Context<SetCustodyPermissions>, permissions: Option<Permissions>) -> Result<()> {
+        let instruction_hash = get_instruction_hash("set_custody_permissions", &[ctx.accounts.custody.key()], &permissions)?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        let custody = &mut ctx.accounts.custody;
+        custody.permissions = permissions;
+
+        Ok(())
+    }
+
+    //admin instructions
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_custody_config(
+        ctx: Context<SetCustodyConfig>,
+        is_stable: bool,
+        oracle: Pubkey,
+        oracle_type: OracleType,
+        feed_id: Option<String>,
+        pricing: PricingParams,
+        fees: Fees,
+        borrow_rate: BorrowRateParams,
+    ) -> Result<()> {
+        validate_oracle_config(oracle, &oracle_type, &feed_id)?;
+        validate_custody_config(&pricing, &fees, &borrow_rate)?;
+
+        let instruction_hash = get_instruction_hash("set_custody_config", &[ctx.accounts.custody.key()], &(is_stable, oracle, oracle_type.clone(), feed_id.clone(), pricing.clone(), fees.clone(), borrow_rate.clone()))?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+
+        let clock = Clock::get()?;
+        let custody = &mut ctx.accounts.custody;
+
+        // accrue under the old rates before they change
+        update_borrow_rate(custody, clock.unix_timestamp)?;
+        update_funding_rate(custody, clock.unix_timestamp)?;
+
+        // price state is owned by update_price, keep it as is
+        let mut new_pricing = pricing;
+        new_pricing.current_price = custody.pricing.current_price;
+        new_pricing.ema_price = custody.pricing.ema_price;
+        new_pricing.last_update_time = custody.pricing.last_update_time;
+
+        emit!(CustodyConfigUpdated {
+            custody: custody.key(),
+            old_is_stable: custody.is_stable,
+            new_is_stable: is_stable,
+            old_oracle: custody.oracle,
+            new_oracle: oracle,
+            old_oracle_type: custody.oracle_type.clone(),
+            new_oracle_type: oracle_type.clone(),
+            old_feed_id: custody.feed_id.clone(),
+            new_feed_id: feed_id.clone(),
+            old_pricing: custody.pricing.clone(),
+            new_pricing: new_pricing.clone(),
+            old_fees: custody.fees.clone(),
+            new_fees: fees.clone(),
+            old_borrow_rate: custody.borrow_rate.clone(),
+            new_borrow_rate: borrow_rate.clone(),
+        });
+
+        custody.is_stable = is_stable;
+        custody.oracle = oracle;
+        custody.oracle_type = oracle_type;
+        custody.feed_id = feed_id;
+        custody.pricing = new_pricing;
+        custody.fees = fees;
+        custody.borrow_rate = borrow_rate;
+
+        Ok(())
+    }
+
     //admin instructions
     pub fn update_price(ctx: Context<UpdatePrice>, new_price: u64) -> Result<()> {
         require!(new_price > 0, PerpError::InvalidPrice);
 
+        let instruction_hash = get_instruction_hash("update_price", &[ctx.accounts.custody.key()], &new_price)?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+
         let custody = &mut ctx.accounts.custody;
         let clock = Clock::get()?;
 
-        //update EMA price simple impl
+        let previous_price = custody.pricing.current_price;
+        if trip_circuit_breaker(custody, previous_price, new_price)? {
+            return Ok(());
+        }
+
         let time_diff = clock.unix_timestamp - custody.pricing.last_update_time;
         if time_diff > 0 {
-            let alpha = std::cmp::min(time_diff as u64, 3600) * 1000 / 3600; // max 1 hour smoothing
-            custody.pricing.ema_price = (custody.pricing.ema_price * (1000 - alpha) + new_price * alpha) / 1000;
+            custody.pricing.ema_price = calculate_ema(custody.pricing.ema_price, new_price, time_diff)?;
         }
 
         custody.pricing.current_price = new_price;
@@ -158,31 +406,116 @@ pub mod perpetuals {
         Ok(())
     }
 
+    //admin instructions
+    pub fn reset_circuit_breaker(ctx: Context<ResetCircuitBreaker>, new_price: u64) -> Result<()> {
+        require!(new_price > 0, PerpError::InvalidPrice);
+
+        let instruction_hash = get_instruction_hash("reset_circuit_breaker", &[ctx.accounts.custody.key()], &new_price)?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        let custody = &mut ctx.accounts.custody;
+        require!(custody.trading_halted, PerpError::TradingNotHalted);
+
+        // Restart pricing from the admin-confirmed price
+        custody.trading_halted = false;
+        custody.pricing.current_price = new_price;
+        custody.pricing.ema_price = new_price;
+        custody.pricing.last_update_time = Clock::get()?.unix_timestamp;
+
+        Ok(())
+    }
+
+    //admin instructions
+    // Price pushes are frequent, so any single admin can act as the keeper here
+    pub fn set_custom_oracle_price(
+        ctx: Context<SetCustomOraclePrice>,
+        price: u64,
+        expo: i32,
+        conf: u64,
+        ema: u64,
+        publish_time: i64,
+    ) -> Result<()> {
+        require!(price > 0 && ema > 0, PerpError::InvalidPrice);
+
+        let clock = Clock::get()?;
+        require!(publish_time <= clock.unix_timestamp, PerpError::InvalidPrice);
+
+        let oracle = &mut ctx.accounts.oracle_account;
+        require!(publish_time >= oracle.publish_time, PerpError::PriceTooOld);
+
+        // Same deviation band as update_price, against the last published price
+        let previous_price = if oracle.price > 0 {
+            scale_oracle_price(oracle.price, oracle.expo)?
+        } else {
+            ctx.accounts.custody.pricing.current_price
+        };
+        if trip_circuit_breaker(&mut ctx.accounts.custody, previous_price, scale_oracle_price(price, expo)?)? {
+            return Ok(());
+        }
+
+        oracle.price = price;
+        oracle.expo = expo;
+        oracle.conf = conf;
+        oracle.ema = ema;
+        oracle.publish_time = publish_time;
+
+        Ok(())
+    }
+
     //public instructions
     pub fn add_liquidity(ctx: Context<AddLiquidity>, amount_in: u64, min_lp_amount_out: u64) -> Result<()> {
         require!(amount_in > 0, PerpError::InvalidAmount);
-        require!(ctx.accounts.perpetuals.permissions.allow_add_liquidity, PerpError::ActionNotAllowed);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_add_liquidity
+        )?;
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
 
         let custody = &ctx.accounts.custody;
         let pool = &ctx.accounts.pool;
 
-        //calculate LP tokens based on pool value 
-        let pool_value = calculate_pool_value(&pool, &[custody.clone()])?;
+        //calculate LP tokens based on pool value before the deposit
+        let pool_value = calculate_pool_value(pool, ctx.remaining_accounts, &clock)?;
         let lp_suppy = ctx.accounts.lp_token_mint.supply;
 
+        //calculate fee
+        let fee_amount = calculate_fee(amount_in, custody.fees.add_liquidity)?;
+        let net_amount = amount_in - fee_amount;
+
+        // Deposits are valued at the lower of spot and EMA so LPs can't mint against an inflated price
+        let oracle_price = get_oracle_price(custody, &ctx.accounts.oracle_account, &clock)?;
+        let token_price = get_min_price(custody, &oracle_price);
+        let amount_in_usd = token_amount_to_usd(amount_in, custody.decimals, token_price)?;
+        let deposit_usd = token_amount_to_usd(net_amount, custody.decimals, token_price)?;
+
         let lp_amount_out = if lp_suppy == 0 {
-            amount_in // intial LP tokens 1:1
+            deposit_usd // intial LP tokens 1:1 with USD value
         } else {
-            //lp tokens = (amount_in + total_lp_supply) / pool_value
-            (amount_in as u128 * lp_suppy as u128 / pool_value as u128) as u64
+            require!(pool_value > 0, PerpError::InsufficientLiquidity);
+            //lp tokens = deposit_usd * total_lp_supply / pool_value
+            (deposit_usd as u128)
+                .checked_mul(lp_suppy as u128)
+                .ok_or(PerpError::MathOverflow)?
+                .checked_div(pool_value as u128)
+                .ok_or(PerpError::MathOverflow)?
+                .try_into()
+                .map_err(|_| PerpError::MathOverflow)?
         };
 
+        require!(lp_amount_out > 0, PerpError::InvalidAmount);
         require!(lp_amount_out >= min_lp_amount_out, PerpError::SlippageExceeded);
 
-        //calculate fee
-        let fee_amount = amount_in * custody.fees.add_liquidity / BPS_PRECISION;
-        let net_amount = amount_in - fee_amount;
-
         // transfer tokens form user to custody 
         let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_accounts = Transfer {
@@ -219,8 +552,11 @@ pub mod perpetuals {
         //update custody assets
         let custody_mut = &mut ctx.accounts.custody;
         custody_mut.assets.owned = custody_mut.assets.owned.checked_add(net_amount).ok_or(PerpError::MathOverflow)?;
-        custody_mut.assets.protocol_fees = custody_mut.assets.protocol_fees.checked_add(fee_amount).ok_or(PerpError::MathOverflow)?;
-        custody_mut.volume_stats.add_liquidity_usd = custody_mut.volume_stats.add_liquidity_usd.checked_add(amount_in as u128).ok_or(PerpError::MathOverflow)?;
+        collect_fee(custody_mut, fee_amount)?;
+        custody_mut.volume_stats.add_liquidity_usd = custody_mut.volume_stats.add_liquidity_usd.checked_add(amount_in_usd as u128).ok_or(PerpError::MathOverflow)?;
+
+        let pool_mut = &mut ctx.accounts.pool;
+        pool_mut.aum_usd = pool_value.checked_add(deposit_usd).ok_or(PerpError::MathOverflow)?;
 
         Ok(())
     }
@@ -228,7 +564,15 @@ pub mod perpetuals {
     //public instructions
     pub fn remove_liquidity(ctx: Context<RemoveLiquidity>, lp_amount_in: u64, min_amount_out: u64) -> Result<()> {
         require!(lp_amount_in > 0, PerpError::InvalidAmount);
-        require!(ctx.accounts.perpetuals.permissions.allow_remove_liquidity, PerpError::ActionNotAllowed);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_remove_liquidity
+        )?;
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
 
         let custody = &ctx.accounts.custody;
         let pool = &ctx.accounts.pool;
@@ -236,16 +580,28 @@ pub mod perpetuals {
 
         require!(lp_supply > 0, PerpError::InsufficientLiquidity);
 
-        //calculate tokens to withdraw: (lp_amount * custody_balance) / lp_supply
-        let custody_balance = ctx.accounts.custody_token_account.amount;
-        let gross_amount_out = (lp_amount_in as u128 * custody_balance as u128 / lp_supply as u128) as u64;
+        //calculate USD to withdraw: (lp_amount * pool_value) / lp_supply
+        let pool_value = calculate_pool_value(pool, ctx.remaining_accounts, &clock)?;
+        let withdraw_usd: u64 = (lp_amount_in as u128)
+            .checked_mul(pool_value as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(lp_supply as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .try_into()
+            .map_err(|_| PerpError::MathOverflow)?;
+
+        // Withdrawals are valued at the higher of spot and EMA, paying out fewer tokens
+        let oracle_price = get_oracle_price(custody, &ctx.accounts.oracle_account, &clock)?;
+        let token_price = get_max_price(custody, &oracle_price);
+        let gross_amount_out = usd_to_token_amount(withdraw_usd, custody.decimals, token_price)?;
 
         //calculate fee
-        let fee_amount = gross_amount_out * custody.fees.remove_liquidity / BPS_PRECISION;
+        let fee_amount = calculate_fee(gross_amount_out, custody.fees.remove_liquidity)?;
         let amount_out = gross_amount_out - fee_amount;
 
         require!(amount_out >= min_amount_out, PerpError::SlippageExceeded);
-        require!(custody_balance >= gross_amount_out, PerpError::InsufficientLiquidity);
+        require!(available_liquidity(custody) >= gross_amount_out, PerpError::InsufficientLiquidity);
+        require!(ctx.accounts.custody_token_account.amount >= gross_amount_out, PerpError::InsufficientLiquidity);
 
         //burn lp tokens
         let cpi_program = ctx.accounts.token_program.to_account_info();
@@ -281,25 +637,168 @@ pub mod perpetuals {
         // Update custody assets
         let custody_mut = &mut ctx.accounts.custody;
         custody_mut.assets.owned = custody_mut.assets.owned.saturating_sub(gross_amount_out);
-        custody_mut.assets.protocol_fees = custody_mut.assets.protocol_fees.checked_add(fee_amount).ok_or(PerpError::MathOverflow)?;
-        custody_mut.volume_stats.remove_liquidity_usd = custody_mut.volume_stats.remove_liquidity_usd.checked_add(gross_amount_out as u128).ok_or(PerpError::MathOverflow)?;
+        collect_fee(custody_mut, fee_amount)?;
+        custody_mut.volume_stats.remove_liquidity_usd = custody_mut.volume_stats.remove_liquidity_usd.checked_add(withdraw_usd as u128).ok_or(PerpError::MathOverflow)?;
+
+        let pool_mut = &mut ctx.accounts.pool;
+        pool_mut.aum_usd = pool_value.saturating_sub(withdraw_usd);
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn swap(ctx: Context<Swap>, amount_in: u64, min_amount_out: u64) -> Result<()> {
+        require!(amount_in > 0, PerpError::InvalidAmount);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.receiving_custody,
+            |p| p.allow_swap
+        )?;
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.dispensing_custody,
+            |p| p.allow_swap
+        )?;
+        require_keys_neq!(
+            ctx.accounts.receiving_custody.key(),
+            ctx.accounts.dispensing_custody.key(),
+            PerpError::InvalidSwapCustody
+        );
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.receiving_custody, clock.unix_timestamp)?;
+        update_borrow_rate(&mut ctx.accounts.dispensing_custody, clock.unix_timestamp)?;
+
+        let receiving_custody = &ctx.accounts.receiving_custody;
+        let dispensing_custody = &ctx.accounts.dispensing_custody;
+
+        let price_in = get_oracle_price(
+            receiving_custody,
+            &ctx.accounts.receiving_oracle_account,
+            &clock
+        )?;
+        let price_out = get_oracle_price(
+            dispensing_custody,
+            &ctx.accounts.dispensing_oracle_account,
+            &clock
+        )?;
+
+        // Apply spread: token in is valued lower, token out is priced higher
+        let price_in = apply_spread(get_min_price(receiving_custody, &price_in), receiving_custody.pricing.swap_spread, false)?;
+        let price_out = apply_spread(get_max_price(dispensing_custody, &price_out), dispensing_custody.pricing.swap_spread, true)?;
+
+        // Stable fees only apply when both legs are stable assets
+        let is_stable_swap = receiving_custody.is_stable && dispensing_custody.is_stable;
+        let (fee_in_bps, fee_out_bps) = if is_stable_swap {
+            (receiving_custody.fees.stable_swap_in, dispensing_custody.fees.stable_swap_out)
+        } else {
+            (receiving_custody.fees.swap_in, dispensing_custody.fees.swap_out)
+        };
+
+        let fee_in = calculate_fee(amount_in, fee_in_bps)?;
+        let net_amount_in = amount_in
+            .checked_sub(fee_in)
+            .ok_or(PerpError::MathOverflow)?;
+
+        let swap_usd = token_amount_to_usd(net_amount_in, receiving_custody.decimals, price_in)?;
+        let gross_amount_out = usd_to_token_amount(swap_usd, dispensing_custody.decimals, price_out)?;
+
+        let fee_out = calculate_fee(gross_amount_out, fee_out_bps)?;
+        let amount_out = gross_amount_out
+            .checked_sub(fee_out)
+            .ok_or(PerpError::MathOverflow)?;
+
+        require!(amount_out > 0, PerpError::InvalidAmount);
+        require!(amount_out >= min_amount_out, PerpError::SlippageExceeded);
+        require!(available_liquidity(dispensing_custody) >= gross_amount_out, PerpError::InsufficientLiquidity);
+        require!(ctx.accounts.dispensing_custody_token_account.amount >= amount_out, PerpError::InsufficientLiquidity);
+
+        // transfer tokens from user to receiving custody
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.funding_account.to_account_info(),
+                to: ctx.accounts.receiving_custody_token_account.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        );
+        transfer(transfer_ctx, amount_in)?;
+
+        // transfer tokens from dispensing custody to user
+        let pool_key = ctx.accounts.pool.key();
+        let dispensing_mint_key = ctx.accounts.dispensing_custody_token_mint.key();
+        let custody_seeds = &[
+            b"custody".as_ref(),
+            pool_key.as_ref(),
+            dispensing_mint_key.as_ref(),
+            &[dispensing_custody.bump],
+        ];
+        let signer = &[&custody_seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.dispensing_custody_token_account.to_account_info(),
+                to: ctx.accounts.receiving_account.to_account_info(),
+                authority: ctx.accounts.dispensing_custody.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer(transfer_ctx, amount_out)?;
 
+        // Update receiving custody
+        let receiving_custody = &mut ctx.accounts.receiving_custody;
+        receiving_custody.assets.owned = receiving_custody.assets.owned
+            .checked_add(net_amount_in)
+            .ok_or(PerpError::MathOverflow)?;
+        collect_fee(receiving_custody, fee_in)?;
+        receiving_custody.volume_stats.swap_usd = receiving_custody.volume_stats.swap_usd
+            .checked_add(swap_usd as u128)
+            .ok_or(PerpError::MathOverflow)?;
+
+        // Update dispensing custody
+        let dispensing_custody = &mut ctx.accounts.dispensing_custody;
+        dispensing_custody.assets.owned = dispensing_custody.assets.owned
+            .checked_sub(gross_amount_out)
+            .ok_or(PerpError::MathOverflow)?;
+        collect_fee(dispensing_custody, fee_out)?;
+        dispensing_custody.volume_stats.swap_usd = dispensing_custody.volume_stats.swap_usd
+            .checked_add(swap_usd as u128)
+            .ok_or(PerpError::MathOverflow)?;
 
         Ok(())
     }
 
     //public instructions
-    pub fn open_position(ctx: Context<OpenPosition>, side: Side, collateral_amount: u64, leverage: u64, acceptable_price: u64) -> Result<()> {
-        require!(leverage > 0 && leverage <= MAX_LEVERAGE as u64, PerpError::InvalidLeverage);
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        side: Side,
+        index: u64,
+        collateral_amount: u64,
+        leverage: u64,
+        acceptable_price: u64,
+    ) -> Result<()> {
+        require!((BPS_PRECISION..=MAX_LEVERAGE).contains(&leverage), PerpError::InvalidLeverage);
         require!(collateral_amount >= MIN_COLLATERAL_SOL, PerpError::InvalidCollateralAmount);
-        require!(ctx.accounts.perpetuals.permissions.allow_open_position, PerpError::ActionNotAllowed);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_open_position
+        )?;
 
         let clock = Clock::get()?;
-        let current_price = get_oracle_price(
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
             &ctx.accounts.custody, 
             &ctx.accounts.oracle_account, 
             &clock
         )?;
+        let current_price = get_entry_price(&ctx.accounts.custody, &oracle_price, &side)?;
 
         // Check slippage
         match side {
@@ -311,15 +810,15 @@ pub mod perpetuals {
             }
         }
 
-        let size_usd = collateral_amount
-            .checked_mul(leverage)
-            .ok_or(PerpError::MathOverflow)?;
-
-        let opening_fee = size_usd  
-            .checked_mul(ctx.accounts.custody.fees.open_position)
-            .ok_or(PerpError::MathOverflow)?
-            .checked_div(BPS_PRECISION)
-            .ok_or(PerpError::MathOverflow)?;
+        let opening_fee = book_open_position(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.custody,
+            side,
+            collateral_amount,
+            leverage,
+            &oracle_price,
+            clock.unix_timestamp
+        )?;
 
         let total_collateral_needed = collateral_amount
             .checked_add(opening_fee)
@@ -335,83 +834,46 @@ pub mod perpetuals {
         let transfer_ctx = CpiContext::new(cpi_program, cpi_accounts);
         transfer(transfer_ctx, total_collateral_needed)?;
 
-        // Update custody
-        let custody = &mut ctx.accounts.custody;
-        custody.assets.collateral = custody.assets.collateral
-            .checked_add(collateral_amount)
-            .ok_or(PerpError::MathOverflow)?;
-        custody.assets.protocol_fees = custody.assets.protocol_fees
-            .checked_add(opening_fee)
-            .ok_or(PerpError::MathOverflow)?;
-
-        // Update open interest
-        match side {
-            Side::Long => {
-                custody.trade_stats.oi_long_usd = custody.trade_stats.oi_long_usd
-                    .checked_add(size_usd)
-                    .ok_or(PerpError::MathOverflow)?;
-            },
-            Side::Short => {
-                custody.trade_stats.oi_short_usd = custody.trade_stats.oi_short_usd
-                    .checked_add(size_usd)
-                    .ok_or(PerpError::MathOverflow)?;
-            }
-        }
-
-        // Initialize position
         let position = &mut ctx.accounts.position;
         position.owner = ctx.accounts.owner.key();
         position.pool = ctx.accounts.pool.key();
         position.custody = ctx.accounts.custody.key();
-        position.side = side;
-        position.collateral_amount = collateral_amount;
-        position.leverage = leverage;
-        position.size_usd = size_usd;
-        position.entry_price = current_price;
-        position.entry_timestamp = clock.unix_timestamp;
-        position.unrealized_pnl = 0;
+        position.index = index;
         position.bump = ctx.bumps.position;
 
+        // Track the position so clients can list a user's positions
+        let registry = &mut ctx.accounts.position_registry;
+        if registry.owner == Pubkey::default() {
+            registry.owner = ctx.accounts.owner.key();
+            registry.bump = ctx.bumps.position_registry;
+        }
+        require!(registry.positions.len() < MAX_POSITIONS, PerpError::MaxPositionsExceeded);
+        registry.positions.push(ctx.accounts.position.key());
+
         Ok(())
     }
 
     //public instructions
     pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
-        require!(ctx.accounts.perpetuals.permissions.allow_close_position, PerpError::ActionNotAllowed);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_close_position
+        )?;
         
         let clock = Clock::get()?;
-        let current_price = get_oracle_price(
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
             &ctx.accounts.custody, 
             &ctx.accounts.oracle_account, 
             &clock
         )?;
+        let current_price = get_exit_price(&ctx.accounts.custody, &oracle_price, &ctx.accounts.position.side)?;
 
-        let position = &ctx.accounts.position;
-        // Calculate PnL
-        let pnl = calculate_pnl(position, current_price)?;
-
-        let closing_fee = position.size_usd
-            .checked_mul(ctx.accounts.custody.fees.close_position)
-            .ok_or(PerpError::MathOverflow)?
-            .checked_div(BPS_PRECISION)
-            .ok_or(PerpError::MathOverflow)?;
-
-        let mut transfer_amount = position.collateral_amount;
-
-        if pnl >= 0 {
-            transfer_amount = transfer_amount
-                .checked_add(pnl as u64)
-                .ok_or(PerpError::MathOverflow)?;
-        } else {
-            transfer_amount = transfer_amount
-                .saturating_sub((-pnl) as u64);
-        }
-
-        transfer_amount = transfer_amount.saturating_sub(closing_fee);
-
-        // Get custody balance
-        let custody_balance = ctx.accounts.custody_token_account.amount;
-        transfer_amount = transfer_amount.min(custody_balance);
+        let transfer_amount = book_close_position(&ctx.accounts.position, &mut ctx.accounts.custody, current_price)?;
 
         // Transfer tokens to user if amount > 0 - FIX: Use custody as authority
         let pool_key = ctx.accounts.pool.key();
@@ -437,24 +899,8 @@ pub mod perpetuals {
             token::transfer(transfer_ctx, transfer_amount)?;
         }
 
-        // Update custody
-        let custody = &mut ctx.accounts.custody;
-        custody.assets.collateral = custody.assets.collateral.saturating_sub(position.collateral_amount);
-        custody.assets.protocol_fees = custody.assets.protocol_fees
-            .checked_add(closing_fee)
-            .ok_or(PerpError::MathOverflow)?;
-
-        // Update open interest
-        match position.side {
-            Side::Long => {
-                custody.trade_stats.oi_long_usd = custody.trade_stats.oi_long_usd
-                    .saturating_sub(position.size_usd);
-            },
-            Side::Short => {
-                custody.trade_stats.oi_short_usd = custody.trade_stats.oi_short_usd
-                    .saturating_sub(position.size_usd);
-            }
-        }
+        let position_key = ctx.accounts.position.key();
+        ctx.accounts.position_registry.positions.retain(|p| *p != position_key);
         
         Ok(())
     }
@@ -462,14 +908,18 @@ pub mod perpetuals {
     //public instructions
     pub fn liquidate_position(ctx: Context<LiquidatePosition>) -> Result<()> {
         let clock = Clock::get()?;
-        let current_price = get_oracle_price(
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
             &ctx.accounts.custody, 
             &ctx.accounts.oracle_account, 
             &clock
         )?;
+        let current_price = get_exit_price(&ctx.accounts.custody, &oracle_price, &ctx.accounts.position.side)?;
 
         let position = &ctx.accounts.position;
-        let liquidation_price = calculate_liquidation_price(position, LIQUIDATION_THRESHOLD)?;
+        let liquidation_price = calculate_liquidation_price(position, &ctx.accounts.custody, LIQUIDATION_THRESHOLD)?;
 
         let can_liquidate = match position.side {
             Side::Long => current_price <= liquidation_price,
@@ -478,21 +928,42 @@ pub mod perpetuals {
 
         require!(can_liquidate, PerpError::PositionNotLiquidatable);
 
-        let pnl = calculate_pnl(position, current_price)?;
+        let custody = &ctx.accounts.custody;
+        let pnl = signed_usd_to_token_amount(calculate_pnl(position, current_price)?, custody.decimals, current_price)?;
+
+        // In liquidation, user gets remaining collateral after losses and borrow fees
+        let loss = if pnl < 0 {
+            pnl.unsigned_abs().min(position.collateral_amount)
+        } else {
+            0
+        };
+        let remaining_collateral = position.collateral_amount - loss;
+
+        let borrow_fee = usd_to_token_amount(calculate_borrow_fee(position, custody)?, custody.decimals, current_price)?
+            .min(remaining_collateral);
+        let remaining_collateral = remaining_collateral - borrow_fee;
 
-        // In liquidation, user gets remaining collateral after losses
-        let remaining_collateral = if pnl < 0 {
-            position.collateral_amount.saturating_sub((-pnl) as u64)
+        let funding_payment = signed_usd_to_token_amount(
+            calculate_funding_payment(position, custody)?,
+            custody.decimals,
+            current_price
+        )?;
+        let (remaining_collateral, funding_settled) = if funding_payment >= 0 {
+            let paid = (funding_payment as u64).min(remaining_collateral);
+            (remaining_collateral - paid, paid as i64)
         } else {
-            position.collateral_amount
+            let received = remaining_collateral
+                .checked_add(funding_payment.unsigned_abs())
+                .ok_or(PerpError::MathOverflow)?;
+            (received, funding_payment)
         };
 
         let liquidation_fee = remaining_collateral.min(
-            position.size_usd
-                .checked_mul(ctx.accounts.custody.fees.liquidation)
-                .ok_or(PerpError::MathOverflow)?
-                .checked_div(BPS_PRECISION)
-                .ok_or(PerpError::MathOverflow)?
+            usd_to_token_amount(
+                calculate_fee(position.size_usd, custody.fees.liquidation)?,
+                custody.decimals,
+                current_price
+            )?
         );
         let user_amount = remaining_collateral.saturating_sub(liquidation_fee);
 
@@ -547,640 +1018,3229 @@ pub mod perpetuals {
         // Update custody
         let custody = &mut ctx.accounts.custody;
         custody.assets.collateral = custody.assets.collateral.saturating_sub(position.collateral_amount);
+        collect_fee(custody, borrow_fee)?;
+        custody.assets.locked = custody.assets.locked.saturating_sub(position.locked_amount);
+        settle_pool_owned(custody, loss as i64)?;
+        settle_pool_owned(custody, funding_settled)?;
 
         // Update open interest
-        match position.side {
-            Side::Long => {
-                custody.trade_stats.oi_long_usd = custody.trade_stats.oi_long_usd
-                    .saturating_sub(position.size_usd);
-            },
-            Side::Short => {
-                custody.trade_stats.oi_short_usd = custody.trade_stats.oi_short_usd
-                    .saturating_sub(position.size_usd);
-            }
-        }
+        remove_open_interest(custody, &position.side, position.size_usd, position.entry_price)?;
+
+        let position_key = ctx.accounts.position.key();
+        ctx.accounts.position_registry.positions.retain(|p| *p != position_key);
 
         Ok(())
     }
 
     //public instructions
-    pub fn update_position(ctx: Context<UpdatePosition>) -> Result<()> {
+    pub fn increase_position(
+        ctx: Context<IncreasePosition>,
+        collateral_amount: u64,
+        size_usd_delta: u64,
+        acceptable_price: u64,
+    ) -> Result<()> {
+        require!(size_usd_delta > 0, PerpError::InvalidAmount);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_size_change
+        )?;
+
         let clock = Clock::get()?;
-        let current_price = get_oracle_price(
-            &ctx.accounts.custody, 
-            &ctx.accounts.oracle_account, 
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
+            &ctx.accounts.custody,
+            &ctx.accounts.oracle_account,
             &clock
         )?;
-        let position = &mut ctx.accounts.position;
-        position.unrealized_pnl = calculate_pnl(position, current_price)?;
+        let side = ctx.accounts.position.side;
+        let current_price = get_entry_price(&ctx.accounts.custody, &oracle_price, &side)?;
+        let exit_price = get_exit_price(&ctx.accounts.custody, &oracle_price, &side)?;
+
+        match side {
+            Side::Long => require!(current_price <= acceptable_price, PerpError::PriceSlippageExceeded),
+            Side::Short => require!(current_price >= acceptable_price, PerpError::PriceSlippageExceeded),
+        }
+
+        // Costs accrued on the old size are charged before the size changes, at the exit price as on close
+        settle_position_costs(&mut ctx.accounts.position, &mut ctx.accounts.custody, exit_price)?;
+
+        let custody = &ctx.accounts.custody;
+        let position = &ctx.accounts.position;
+
+        // Added collateral is valued at the lower price, as in add_collateral
+        let collateral_price = get_min_price(custody, &oracle_price);
+        let added_collateral_usd = token_amount_to_usd(collateral_amount, custody.decimals, collateral_price)?;
+        let collateral_usd = position.collateral_usd
+            .checked_add(added_collateral_usd)
+            .ok_or(PerpError::MathOverflow)?;
+        let size_usd = position.size_usd
+            .checked_add(size_usd_delta)
+            .ok_or(PerpError::MathOverflow)?;
+
+        let leverage = calculate_leverage(size_usd, collateral_usd)?;
+        require!(leverage <= MAX_LEVERAGE, PerpError::InvalidLeverage);
+        check_leverage(custody, leverage)?;
+        check_open_interest_limits(custody, &side, size_usd_delta)?;
+
+        let opening_fee = usd_to_token_amount(
+            calculate_fee(size_usd_delta, custody.fees.open_position)?,
+            custody.decimals,
+            current_price
+        )?;
+        let locked_amount = usd_to_token_amount(size_usd_delta, custody.decimals, current_price)?;
+        require!(available_liquidity(custody) >= locked_amount, PerpError::InsufficientLiquidity);
+
+        // Quantity weighted, so PnL on the combined size matches the two legs
+        let quantity = position_quantity(position.size_usd, position.entry_price)?
+            .checked_add(position_quantity(size_usd_delta, current_price)?)
+            .ok_or(PerpError::MathOverflow)?;
+        let entry_price: u64 = (size_usd as u128)
+            .checked_mul(PRICE_PRECISION as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(quantity)
+            .ok_or(PerpError::MathOverflow)?
+            .try_into()
+            .map_err(|_| PerpError::MathOverflow)?;
+
+        let total_amount = collateral_amount
+            .checked_add(opening_fee)
+            .ok_or(PerpError::MathOverflow)?;
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.collateral_account.to_account_info(),
+                to: ctx.accounts.custody_token_account.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info()
+            }
+        );
+        transfer(transfer_ctx, total_amount)?;
+
+        let custody = &mut ctx.accounts.custody;
+        let position = &mut ctx.accounts.position;
+
+        remove_open_interest(custody, &side, position.size_usd, position.entry_price)?;
+        add_open_interest(custody, &side, size_usd, entry_price)?;
+
+        custody.assets.collateral = custody.assets.collateral
+            .checked_add(collateral_amount)
+            .ok_or(PerpError::MathOverflow)?;
+        collect_fee(custody, opening_fee)?;
+        custody.assets.locked = custody.assets.locked
+            .checked_add(locked_amount)
+            .ok_or(PerpError::MathOverflow)?;
+        custody.volume_stats.open_position_usd = custody.volume_stats.open_position_usd
+            .checked_add(size_usd_delta as u128)
+            .ok_or(PerpError::MathOverflow)?;
+
+        position.collateral_amount = position.collateral_amount
+            .checked_add(collateral_amount)
+            .ok_or(PerpError::MathOverflow)?;
+        position.collateral_usd = collateral_usd;
+        position.size_usd = size_usd;
+        position.leverage = leverage;
+        position.entry_price = entry_price;
+        position.locked_amount = position.locked_amount
+            .checked_add(locked_amount)
+            .ok_or(PerpError::MathOverflow)?;
+        position.liquidation_price = calculate_liquidation_price(position, custody, LIQUIDATION_THRESHOLD)?;
+
+        // An underwater position can't be grown past its liquidation price
+        check_clear_of_liquidation(position, exit_price)?;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn decrease_position(ctx: Context<DecreasePosition>, size_usd_delta: u64, acceptable_price: u64) -> Result<()> {
+        require!(
+            size_usd_delta > 0 && size_usd_delta < ctx.accounts.position.size_usd,
+            PerpError::InvalidAmount
+        );
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_size_change
+        )?;
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
+            &ctx.accounts.custody,
+            &ctx.accounts.oracle_account,
+            &clock
+        )?;
+        let side = ctx.accounts.position.side;
+        let current_price = get_exit_price(&ctx.accounts.custody, &oracle_price, &side)?;
+
+        match side {
+            Side::Long => require!(current_price >= acceptable_price, PerpError::PriceSlippageExceeded),
+            Side::Short => require!(current_price <= acceptable_price, PerpError::PriceSlippageExceeded),
+        }
+
+        settle_position_costs(&mut ctx.accounts.position, &mut ctx.accounts.custody, current_price)?;
+
+        let custody = &ctx.accounts.custody;
+        let position = &ctx.accounts.position;
+
+        // Collateral, lock and PnL are released in proportion to the size closed
+        let collateral_out = proportional_amount(position.collateral_amount, size_usd_delta, position.size_usd)?;
+        let collateral_usd_out = proportional_amount(position.collateral_usd, size_usd_delta, position.size_usd)?;
+        let locked_out = proportional_amount(position.locked_amount, size_usd_delta, position.size_usd)?;
+
+        let pnl_usd = (calculate_pnl(position, current_price)? as i128)
+            .checked_mul(size_usd_delta as i128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(position.size_usd as i128)
+            .ok_or(PerpError::MathOverflow)?;
+        let pnl = signed_usd_to_token_amount(
+            pnl_usd.try_into().map_err(|_| PerpError::MathOverflow)?,
+            custody.decimals,
+            current_price
+        )?;
+        let closing_fee = usd_to_token_amount(
+            calculate_fee(size_usd_delta, custody.fees.close_position)?,
+            custody.decimals,
+            current_price
+        )?;
+
+        let mut transfer_amount = collateral_out;
+        let pnl_settled = if pnl >= 0 {
+            let profit = (pnl as u64).min(locked_out);
+            transfer_amount = transfer_amount
+                .checked_add(profit)
+                .ok_or(PerpError::MathOverflow)?;
+            profit as i64
+        } else {
+            let loss = pnl.unsigned_abs().min(transfer_amount);
+            transfer_amount -= loss;
+            -(loss as i64)
+        };
+        let closing_fee = closing_fee.min(transfer_amount);
+        transfer_amount -= closing_fee;
+
+        if transfer_amount > 0 {
+            let pool_key = ctx.accounts.pool.key();
+            let mint_key = ctx.accounts.mint.key();
+            let custody_seeds = &[
+                b"custody".as_ref(),
+                pool_key.as_ref(),
+                mint_key.as_ref(),
+                &[ctx.accounts.custody.bump],
+            ];
+            let signer = &[&custody_seeds[..]];
+
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.custody_token_account.to_account_info(),
+                    to: ctx.accounts.receiving_account.to_account_info(),
+                    authority: ctx.accounts.custody.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer(transfer_ctx, transfer_amount)?;
+        }
+
+        let custody = &mut ctx.accounts.custody;
+        let position = &mut ctx.accounts.position;
+
+        custody.assets.collateral = custody.assets.collateral.saturating_sub(collateral_out);
+        collect_fee(custody, closing_fee)?;
+        custody.assets.locked = custody.assets.locked.saturating_sub(locked_out);
+        settle_pool_owned(custody, -pnl_settled)?;
+        remove_open_interest(custody, &side, size_usd_delta, position.entry_price)?;
+        custody.volume_stats.close_position_usd = custody.volume_stats.close_position_usd
+            .checked_add(size_usd_delta as u128)
+            .ok_or(PerpError::MathOverflow)?;
+
+        position.collateral_amount -= collateral_out;
+        position.collateral_usd -= collateral_usd_out;
+        position.locked_amount -= locked_out;
+        position.size_usd -= size_usd_delta;
+        position.leverage = calculate_leverage(position.size_usd, position.collateral_usd)?;
+        position.liquidation_price = calculate_liquidation_price(position, custody, LIQUIDATION_THRESHOLD)?;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn add_collateral(ctx: Context<AddCollateral>, collateral_amount: u64) -> Result<()> {
+        require!(collateral_amount > 0, PerpError::InvalidCollateralAmount);
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
+            &ctx.accounts.custody,
+            &ctx.accounts.oracle_account,
+            &clock
+        )?;
+        // Added collateral is valued at the lower price
+        let collateral_price = get_min_price(&ctx.accounts.custody, &oracle_price);
+
+        settle_position_costs(&mut ctx.accounts.position, &mut ctx.accounts.custody, collateral_price)?;
+
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.collateral_account.to_account_info(),
+                to: ctx.accounts.custody_token_account.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info()
+            }
+        );
+        transfer(transfer_ctx, collateral_amount)?;
+
+        let custody = &mut ctx.accounts.custody;
+        let position = &mut ctx.accounts.position;
+        let collateral_usd = token_amount_to_usd(collateral_amount, custody.decimals, collateral_price)?;
+
+        custody.assets.collateral = custody.assets.collateral
+            .checked_add(collateral_amount)
+            .ok_or(PerpError::MathOverflow)?;
+
+        position.collateral_amount = position.collateral_amount
+            .checked_add(collateral_amount)
+            .ok_or(PerpError::MathOverflow)?;
+        position.collateral_usd = position.collateral_usd
+            .checked_add(collateral_usd)
+            .ok_or(PerpError::MathOverflow)?;
+        position.leverage = calculate_leverage(position.size_usd, position.collateral_usd)?;
+        position.liquidation_price = calculate_liquidation_price(position, custody, LIQUIDATION_THRESHOLD)?;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn remove_collateral(ctx: Context<RemoveCollateral>, collateral_amount: u64) -> Result<()> {
+        require!(collateral_amount > 0, PerpError::InvalidCollateralAmount);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_collateral_withdrawal
+        )?;
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
+            &ctx.accounts.custody,
+            &ctx.accounts.oracle_account,
+            &clock
+        )?;
+        let side = ctx.accounts.position.side;
+        let exit_price = get_exit_price(&ctx.accounts.custody, &oracle_price, &side)?;
+        // Withdrawn collateral is valued at the higher price
+        let collateral_price = get_max_price(&ctx.accounts.custody, &oracle_price);
+
+        // Costs come out first so the check sees what is actually left
+        settle_position_costs(&mut ctx.accounts.position, &mut ctx.accounts.custody, collateral_price)?;
+        require!(collateral_amount < ctx.accounts.position.collateral_amount, PerpError::InvalidCollateralAmount);
+
+        let custody = &mut ctx.accounts.custody;
+        let position = &mut ctx.accounts.position;
+        let collateral_usd = token_amount_to_usd(collateral_amount, custody.decimals, collateral_price)?;
+
+        position.collateral_amount -= collateral_amount;
+        position.collateral_usd = position.collateral_usd
+            .checked_sub(collateral_usd)
+            .ok_or(PerpError::InvalidCollateralAmount)?;
+        position.leverage = calculate_leverage(position.size_usd, position.collateral_usd)?;
+        position.liquidation_price = calculate_liquidation_price(position, custody, LIQUIDATION_THRESHOLD)?;
+
+        // The reduced position must stay within leverage limits and clear of liquidation
+        require!(position.leverage <= MAX_LEVERAGE, PerpError::InvalidLeverage);
+        check_leverage(custody, position.leverage)?;
+        check_clear_of_liquidation(position, exit_price)?;
+
+        custody.assets.collateral = custody.assets.collateral.saturating_sub(collateral_amount);
+
+        let pool_key = ctx.accounts.pool.key();
+        let mint_key = ctx.accounts.mint.key();
+        let custody_seeds = &[
+            b"custody".as_ref(),
+            pool_key.as_ref(),
+            mint_key.as_ref(),
+            &[ctx.accounts.custody.bump],
+        ];
+        let signer = &[&custody_seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.custody_token_account.to_account_info(),
+                to: ctx.accounts.receiving_account.to_account_info(),
+                authority: ctx.accounts.custody.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer(transfer_ctx, collateral_amount)?;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn withdraw_pnl(ctx: Context<WithdrawPnl>, pnl_usd: u64) -> Result<()> {
+        require!(pnl_usd > 0, PerpError::InvalidAmount);
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            |p| p.allow_pnl_withdrawal
+        )?;
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
+            &ctx.accounts.custody,
+            &ctx.accounts.oracle_account,
+            &clock
+        )?;
+        let side = ctx.accounts.position.side;
+        let current_price = get_exit_price(&ctx.accounts.custody, &oracle_price, &side)?;
+
+        settle_position_costs(&mut ctx.accounts.position, &mut ctx.accounts.custody, current_price)?;
+
+        let custody = &ctx.accounts.custody;
+        let position = &ctx.accounts.position;
+
+        let pnl = calculate_pnl(position, current_price)?;
+        require!(pnl > 0 && pnl_usd <= pnl as u64, PerpError::InsufficientPnl);
+
+        // Profit comes out of the liquidity locked for this position
+        let amount = usd_to_token_amount(pnl_usd, custody.decimals, current_price)?;
+        require!(amount <= position.locked_amount, PerpError::InsufficientLiquidity);
+        let fee = usd_to_token_amount(
+            calculate_fee(pnl_usd, custody.fees.close_position)?,
+            custody.decimals,
+            current_price
+        )?;
+        let payout = amount.saturating_sub(fee);
+        let fee = amount - payout;
+
+        // Move the entry price so the PnL left on the position is pnl - pnl_usd
+        let remaining_pnl = pnl as u64 - pnl_usd;
+        let size_basis = match side {
+            Side::Long => position.size_usd.checked_add(remaining_pnl),
+            Side::Short => position.size_usd.checked_sub(remaining_pnl),
+        }
+        .ok_or(PerpError::MathOverflow)?;
+        let entry_price: u64 = (position.size_usd as u128)
+            .checked_mul(current_price as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(size_basis as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .try_into()
+            .map_err(|_| PerpError::MathOverflow)?;
+
+        if payout > 0 {
+            let pool_key = ctx.accounts.pool.key();
+            let mint_key = ctx.accounts.mint.key();
+            let custody_seeds = &[
+                b"custody".as_ref(),
+                pool_key.as_ref(),
+                mint_key.as_ref(),
+                &[ctx.accounts.custody.bump],
+            ];
+            let signer = &[&custody_seeds[..]];
+
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.custody_token_account.to_account_info(),
+                    to: ctx.accounts.receiving_account.to_account_info(),
+                    authority: ctx.accounts.custody.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer(transfer_ctx, payout)?;
+        }
+
+        let custody = &mut ctx.accounts.custody;
+        let position = &mut ctx.accounts.position;
+
+        custody.assets.locked = custody.assets.locked.saturating_sub(amount);
+        settle_pool_owned(custody, -(amount as i64))?;
+        collect_fee(custody, fee)?;
+        remove_open_interest(custody, &side, position.size_usd, position.entry_price)?;
+        add_open_interest(custody, &side, position.size_usd, entry_price)?;
+
+        position.locked_amount -= amount;
+        position.entry_price = entry_price;
+        position.unrealized_pnl = remaining_pnl as i64;
+        position.liquidation_price = calculate_liquidation_price(position, custody, LIQUIDATION_THRESHOLD)?;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn update_position(ctx: Context<UpdatePosition>) -> Result<()> {
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
+            &ctx.accounts.custody, 
+            &ctx.accounts.oracle_account, 
+            &clock
+        )?;
+        let current_price = get_exit_price(&ctx.accounts.custody, &oracle_price, &ctx.accounts.position.side)?;
+        // Charge accrued borrow fee and funding against collateral
+        settle_position_costs(&mut ctx.accounts.position, &mut ctx.accounts.custody, current_price)?;
+
+        let custody = &ctx.accounts.custody;
+        let position = &mut ctx.accounts.position;
+        position.unrealized_pnl = calculate_pnl(position, current_price)?;
+        position.liquidation_price = calculate_liquidation_price(position, custody, LIQUIDATION_THRESHOLD)?;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn update_funding(ctx: Context<UpdateFunding>) -> Result<()> {
+        let clock = Clock::get()?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
 
         Ok(())
     }
+
+    //admin instructions
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
+
+        let instruction_hash = get_instruction_hash(
+            "withdraw_fees",
+            &[ctx.accounts.custody.key(), ctx.accounts.receiving_account.key()],
+            &amount
+        )?;
+        if sign_multisig(
+            &mut ctx.accounts.multisig,
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.authority.key(),
+            instruction_hash
+        )? > 0 {
+            return Ok(());
+        }
+
+        let custody = &ctx.accounts.custody;
+        require!(amount <= custody.assets.protocol_fees, PerpError::InsufficientFees);
+
+        // Collateral and LP owned funds must stay in the custody
+        let reserved = custody.assets.collateral
+            .checked_add(custody.assets.owned)
+            .ok_or(PerpError::MathOverflow)?;
+        let remaining = ctx.accounts.custody_token_account.amount
+            .checked_sub(amount)
+            .ok_or(PerpError::InsufficientFees)?;
+        require!(remaining >= reserved, PerpError::InsufficientFees);
+
+        let pool_key = ctx.accounts.pool.key();
+        let custody_seeds = &[
+            b"custody".as_ref(),
+            pool_key.as_ref(),
+            custody.mint.as_ref(),
+            &[custody.bump],
+        ];
+        let signer = &[&custody_seeds[..]];
+
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.custody_token_account.to_account_info(),
+                to: ctx.accounts.receiving_account.to_account_info(),
+                authority: ctx.accounts.custody.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer(transfer_ctx, amount)?;
+
+        let custody = &mut ctx.accounts.custody;
+        custody.assets.protocol_fees = custody.assets.protocol_fees
+            .checked_sub(amount)
+            .ok_or(PerpError::MathOverflow)?;
+
+        Ok(())
+    }
+    //public instructions
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_order(
+        ctx: Context<PlaceOrder>,
+        index: u64,
+        order_type: OrderType,
+        side: Side,
+        position_index: u64,
+        collateral_amount: u64,
+        leverage: u64,
+        trigger_price: u64,
+        execution_fee: u64,
+    ) -> Result<()> {
+        require!(trigger_price > 0, PerpError::InvalidPrice);
+
+        // Open orders escrow the collateral and the worst case opening fee, close orders only the keeper fee
+        let (collateral_amount, leverage, escrow_amount, rent_deposit, position_entry_timestamp) = match order_type {
+            OrderType::Open => {
+                require!((BPS_PRECISION..=MAX_LEVERAGE).contains(&leverage), PerpError::InvalidLeverage);
+                require!(collateral_amount >= MIN_COLLATERAL_SOL, PerpError::InvalidCollateralAmount);
+                check_leverage(&ctx.accounts.custody, leverage)?;
+
+                let opening_fee = max_opening_fee(&ctx.accounts.custody, collateral_amount, leverage)?;
+                let escrow_amount = collateral_amount
+                    .checked_add(opening_fee)
+                    .ok_or(PerpError::MathOverflow)?
+                    .checked_add(execution_fee)
+                    .ok_or(PerpError::MathOverflow)?;
+
+                // Rent for the accounts the keeper creates on execution
+                let rent = Rent::get()?;
+                let rent_deposit = rent.minimum_balance(8 + Position::INIT_SPACE)
+                    .checked_add(rent.minimum_balance(8 + PositionRegistry::INIT_SPACE))
+                    .ok_or(PerpError::MathOverflow)?;
+                (collateral_amount, leverage, escrow_amount, rent_deposit, 0)
+            },
+            OrderType::Close | OrderType::StopLoss => {
+                // Record which position the order closes, so it can't fire on a later one at the same index
+                let position_info = &ctx.accounts.position;
+                require_keys_eq!(*position_info.owner, crate::ID, PerpError::PositionNotFound);
+                let position = Position::try_deserialize(&mut &position_info.try_borrow_data()?[..])?;
+                require!(position.owner == ctx.accounts.owner.key(), PerpError::PositionNotFound);
+
+                (0, 0, execution_fee, 0, position.entry_timestamp)
+            },
+        };
+
+        if rent_deposit > 0 {
+            let transfer_ctx = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.owner.to_account_info(),
+                    to: ctx.accounts.order.to_account_info(),
+                },
+            );
+            anchor_lang::system_program::transfer(transfer_ctx, rent_deposit)?;
+        }
+
+        if escrow_amount > 0 {
+            let transfer_ctx = CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.funding_account.to_account_info(),
+                    to: ctx.accounts.escrow_account.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            );
+            transfer(transfer_ctx, escrow_amount)?;
+        }
+
+        let order = &mut ctx.accounts.order;
+        order.owner = ctx.accounts.owner.key();
+        order.pool = ctx.accounts.pool.key();
+        order.custody = ctx.accounts.custody.key();
+        order.order_type = order_type;
+        order.side = side;
+        order.position_index = position_index;
+        order.collateral_amount = collateral_amount;
+        order.leverage = leverage;
+        order.trigger_price = trigger_price;
+        order.execution_fee = execution_fee;
+        order.rent_deposit = rent_deposit;
+        order.position_entry_timestamp = position_entry_timestamp;
+        order.index = index;
+        order.bump = ctx.bumps.order;
+        order.escrow_bump = ctx.bumps.escrow_account;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn execute_order(ctx: Context<ExecuteOrder>) -> Result<()> {
+        let order_type = ctx.accounts.order.order_type;
+        let side = ctx.accounts.order.side;
+        let allowed: fn(&Permissions) -> bool = match order_type {
+            OrderType::Open => |p| p.allow_open_position,
+            OrderType::Close | OrderType::StopLoss => |p| p.allow_close_position,
+        };
+        check_permission(
+            &ctx.accounts.perpetuals,
+            &ctx.accounts.pool,
+            &ctx.accounts.custody,
+            allowed
+        )?;
+
+        let clock = Clock::get()?;
+        update_borrow_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+        update_funding_rate(&mut ctx.accounts.custody, clock.unix_timestamp)?;
+
+        let oracle_price = get_oracle_price(
+            &ctx.accounts.custody,
+            &ctx.accounts.oracle_account,
+            &clock
+        )?;
+        let execution_price = match order_type {
+            OrderType::Open => get_entry_price(&ctx.accounts.custody, &oracle_price, &side)?,
+            OrderType::Close | OrderType::StopLoss => get_exit_price(&ctx.accounts.custody, &oracle_price, &side)?,
+        };
+        require!(order_triggered(&ctx.accounts.order, execution_price), PerpError::OrderNotTriggered);
+
+        let owner_key = ctx.accounts.owner.key();
+        let pool_key = ctx.accounts.pool.key();
+        let custody_key = ctx.accounts.custody.key();
+        let mint_key = ctx.accounts.mint.key();
+        let order_index = ctx.accounts.order.index.to_le_bytes();
+        let order_seeds = &[
+            b"order".as_ref(),
+            owner_key.as_ref(),
+            pool_key.as_ref(),
+            custody_key.as_ref(),
+            order_index.as_ref(),
+            &[ctx.accounts.order.bump],
+        ];
+        let order_signer = &[&order_seeds[..]];
+
+        let execution_fee = ctx.accounts.order.execution_fee;
+        let mut refund_amount = ctx.accounts.escrow_account.amount
+            .checked_sub(execution_fee)
+            .ok_or(PerpError::InsufficientCollateral)?;
+
+        match order_type {
+            OrderType::Open => {
+                require!(ctx.accounts.position.owner == Pubkey::default(), PerpError::PositionAlreadyExists);
+
+                let collateral_amount = ctx.accounts.order.collateral_amount;
+                let opening_fee = book_open_position(
+                    &mut ctx.accounts.position,
+                    &mut ctx.accounts.custody,
+                    side,
+                    collateral_amount,
+                    ctx.accounts.order.leverage,
+                    &oracle_price,
+                    clock.unix_timestamp
+                )?;
+
+                let total_collateral_needed = collateral_amount
+                    .checked_add(opening_fee)
+                    .ok_or(PerpError::MathOverflow)?;
+                refund_amount = refund_amount
+                    .checked_sub(total_collateral_needed)
+                    .ok_or(PerpError::InsufficientCollateral)?;
+
+                let transfer_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.escrow_account.to_account_info(),
+                        to: ctx.accounts.custody_token_account.to_account_info(),
+                        authority: ctx.accounts.order.to_account_info(),
+                    },
+                    order_signer,
+                );
+                token::transfer(transfer_ctx, total_collateral_needed)?;
+
+                let position = &mut ctx.accounts.position;
+                position.owner = owner_key;
+                position.pool = pool_key;
+                position.custody = custody_key;
+                position.index = ctx.accounts.order.position_index;
+                position.bump = ctx.bumps.position;
+
+                let registry = &mut ctx.accounts.position_registry;
+                let rent = Rent::get()?;
+                let mut keeper_rent = rent.minimum_balance(8 + Position::INIT_SPACE);
+                if registry.owner == Pubkey::default() {
+                    registry.owner = owner_key;
+                    registry.bump = ctx.bumps.position_registry;
+                    keeper_rent = keeper_rent
+                        .checked_add(rent.minimum_balance(8 + PositionRegistry::INIT_SPACE))
+                        .ok_or(PerpError::MathOverflow)?;
+                }
+                require!(registry.positions.len() < MAX_POSITIONS, PerpError::MaxPositionsExceeded);
+                registry.positions.push(ctx.accounts.position.key());
+
+                // Refund the keeper the rent it paid out of the owner's deposit, the rest goes back on close
+                let keeper_rent = keeper_rent.min(ctx.accounts.order.rent_deposit);
+                ctx.accounts.order.sub_lamports(keeper_rent)?;
+                ctx.accounts.keeper.add_lamports(keeper_rent)?;
+            },
+            OrderType::Close | OrderType::StopLoss => {
+                require!(ctx.accounts.position.owner == owner_key, PerpError::PositionNotFound);
+                require!(
+                    ctx.accounts.position.entry_timestamp == ctx.accounts.order.position_entry_timestamp,
+                    PerpError::OrderPositionMismatch
+                );
+
+                let transfer_amount = book_close_position(&ctx.accounts.position, &mut ctx.accounts.custody, execution_price)?;
+
+                if transfer_amount > 0 {
+                    let custody_seeds = &[
+                        b"custody".as_ref(),
+                        pool_key.as_ref(),
+                        mint_key.as_ref(),
+                        &[ctx.accounts.custody.bump],
+                    ];
+                    let signer = &[&custody_seeds[..]];
+
+                    let transfer_ctx = CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.custody_token_account.to_account_info(),
+                            to: ctx.accounts.owner_token_account.to_account_info(),
+                            authority: ctx.accounts.custody.to_account_info(),
+                        },
+                        signer,
+                    );
+                    token::transfer(transfer_ctx, transfer_amount)?;
+                }
+
+                let position_key = ctx.accounts.position.key();
+                ctx.accounts.position_registry.positions.retain(|p| *p != position_key);
+                ctx.accounts.position.close(ctx.accounts.owner.to_account_info())?;
+            },
+        }
+
+        // Pay the keeper and return whatever is left in escrow to the owner
+        if execution_fee > 0 {
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.escrow_account.to_account_info(),
+                    to: ctx.accounts.keeper_token_account.to_account_info(),
+                    authority: ctx.accounts.order.to_account_info(),
+                },
+                order_signer,
+            );
+            token::transfer(transfer_ctx, execution_fee)?;
+        }
+
+        if refund_amount > 0 {
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.escrow_account.to_account_info(),
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: ctx.accounts.order.to_account_info(),
+                },
+                order_signer,
+            );
+            token::transfer(transfer_ctx, refund_amount)?;
+        }
+
+        let close_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.escrow_account.to_account_info(),
+                destination: ctx.accounts.owner.to_account_info(),
+                authority: ctx.accounts.order.to_account_info(),
+            },
+            order_signer,
+        );
+        close_account(close_ctx)?;
+
+        Ok(())
+    }
+
+    //public instructions
+    pub fn cancel_order(ctx: Context<CancelOrder>) -> Result<()> {
+        let owner_key = ctx.accounts.owner.key();
+        let order = &ctx.accounts.order;
+        let order_index = order.index.to_le_bytes();
+        let order_seeds = &[
+            b"order".as_ref(),
+            owner_key.as_ref(),
+            order.pool.as_ref(),
+            order.custody.as_ref(),
+            order_index.as_ref(),
+            &[order.bump],
+        ];
+        let order_signer = &[&order_seeds[..]];
+
+        let escrow_amount = ctx.accounts.escrow_account.amount;
+        if escrow_amount > 0 {
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.escrow_account.to_account_info(),
+                    to: ctx.accounts.receiving_account.to_account_info(),
+                    authority: ctx.accounts.order.to_account_info(),
+                },
+                order_signer,
+            );
+            token::transfer(transfer_ctx, escrow_amount)?;
+        }
+
+        let close_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.escrow_account.to_account_info(),
+                destination: ctx.accounts.owner.to_account_info(),
+                authority: ctx.accounts.order.to_account_info(),
+            },
+            order_signer,
+        );
+        close_account(close_ctx)?;
+
+        Ok(())
+    }
+}
+
+// Account contexts remain the same until AddCustody...
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        space = 8 + Perpetuals::INIT_SPACE,
+        payer = admin,
+        seeds = [b"perpetuals"],
+        bump 
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        init,
+        space = 8 + Multisig::INIT_SPACE,
+        payer = admin,
+        seeds = [b"multisig"],
+        bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetAdminSigners<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeReceiver<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct AddPool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + Pool::INIT_SPACE,
+        seeds = [b"pool", name.as_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        mint::decimals = 6,
+        mint::authority = pool,
+        seeds = [b"lp_token_mint", pool.key().as_ref()],
+        bump
+    )]
+    pub lp_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddCustody<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + Custody::INIT_SPACE,
+        seeds = [b"custody", pool.key().as_ref(), custody_token_mint.key().as_ref()],
+        bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub custody_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        token::mint = custody_token_mint,
+        token::authority = custody,  // FIX: custody is the authority
+        seeds = [b"custody_token_account", pool.key().as_ref(), custody_token_mint.key().as_ref()],
+        bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SetPermissions<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+pub struct SetPoolPermissions<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+pub struct SetCustodyPermissions<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+pub struct SetCustodyConfig<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+pub struct AddLiquidity<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), custody_token_mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub custody_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_token_mint", pool.key().as_ref()],
+        bump = pool.lp_token_bump
+    )]
+    pub lp_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = custody_token_mint,
+        token::authority = owner
+    )]
+    pub funding_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = lp_token_mint,
+        token::authority = owner
+    )]
+    pub lp_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), custody_token_mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), custody_token_mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub custody_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_token_mint", pool.key().as_ref()],
+        bump = pool.lp_token_bump
+    )]
+    pub lp_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = lp_token_mint,
+        token::authority = owner
+    )]
+    pub lp_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = custody_token_mint,
+        token::authority = owner
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), custody_token_mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), receiving_custody_token_mint.key().as_ref()],
+        bump = receiving_custody.bump
+    )]
+    pub receiving_custody: Account<'info, Custody>,
+
+    pub receiving_custody_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), receiving_custody_token_mint.key().as_ref()],
+        bump = receiving_custody.token_account_bump
+    )]
+    pub receiving_custody_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub receiving_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), dispensing_custody_token_mint.key().as_ref()],
+        bump = dispensing_custody.bump
+    )]
+    pub dispensing_custody: Account<'info, Custody>,
+
+    pub dispensing_custody_token_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), dispensing_custody_token_mint.key().as_ref()],
+        bump = dispensing_custody.token_account_bump
+    )]
+    pub dispensing_custody_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub dispensing_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        token::mint = receiving_custody_token_mint,
+        token::authority = owner
+    )]
+    pub funding_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = dispensing_custody_token_mint,
+        token::authority = owner
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePrice<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+pub struct ResetCircuitBreaker<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+}
+
+#[derive(Accounts)]
+pub struct SetCustomOraclePrice<'info> {
+    #[account(
+        mut,
+        constraint = perpetuals.admins.contains(&authority.key()) @ PerpError::MultisigAccountNotAuthorized
+    )]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + CustomOracle::INIT_SPACE,
+        seeds = [b"oracle_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub oracle_account: Account<'info, CustomOracle>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump,
+        constraint = custody.oracle_type == OracleType::Custom @ PerpError::InvalidOracleConfig,
+        constraint = custody.oracle == oracle_account.key() @ PerpError::InvalidOracleAccount
+    )]
+    pub custody: Account<'info, Custody>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(side: Side, index: u64)]
+pub struct OpenPosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Position::INIT_SPACE,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[side as u8],
+            &index.to_le_bytes()
+        ],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + PositionRegistry::INIT_SPACE,
+        seeds = [b"position_registry", owner.key().as_ref()],
+        bump
+    )]
+    pub position_registry: Account<'info, PositionRegistry>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub collateral_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump,
+        has_one = owner,
+        close = owner
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        mut,
+        seeds = [b"position_registry", owner.key().as_ref()],
+        bump = position_registry.bump
+    )]
+    pub position_registry: Account<'info, PositionRegistry>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidatePosition<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            position.owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump,
+        close = liquidator
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        mut,
+        seeds = [b"position_registry", position.owner.key().as_ref()],
+        bump = position_registry.bump
+    )]
+    pub position_registry: Account<'info, PositionRegistry>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = liquidator
+    )]
+    pub liquidator_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = position.owner
+    )]
+    pub position_owner_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct IncreasePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub collateral_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct DecreasePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct AddCollateral<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub collateral_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveCollateral<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawPnl<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePosition<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            position.owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[position.side as u8],
+            &position.index.to_le_bytes()
+        ],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
 }
 
-// Account contexts remain the same until AddCustody...
+#[derive(Accounts)]
+pub struct UpdateFunding<'info> {
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+}
 
 #[derive(Accounts)]
-pub struct Initialize<'info> {
+pub struct WithdrawFees<'info> {
     #[account(mut)]
-    pub admin: Signer<'info>,
+    pub authority: Signer<'info>,
 
     #[account(
-        init,
-        space = 8 + Perpetuals::INIT_SPACE,
-        payer = admin,
         seeds = [b"perpetuals"],
-        bump 
+        bump = perpetuals.bump
     )]
     pub perpetuals: Account<'info, Perpetuals>,
-    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.bump
+    )]
+    pub multisig: Account<'info, Multisig>,
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    // Treasury token account for this mint, owned by Perpetuals::fee_receiver
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = perpetuals.fee_receiver
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-#[instruction(name: String)]
-pub struct AddPool<'info> {
+#[instruction(index: u64, order_type: OrderType, side: Side, position_index: u64)]
+pub struct PlaceOrder<'info> {
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub owner: Signer<'info>,
 
     #[account(
         init,
-        payer = authority,
-        space = 8 + Pool::INIT_SPACE,
-        seeds = [b"pool", name.as_bytes()],
+        payer = owner,
+        space = 8 + Order::INIT_SPACE,
+        seeds = [
+            b"order",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &index.to_le_bytes()
+        ],
         bump
     )]
-    pub pool: Account<'info, Pool>,
+    pub order: Account<'info, Order>,
 
     #[account(
         init,
-        payer = authority,
-        mint::decimals = 6,
-        mint::authority = pool,
-        seeds = [b"lp_token_mint", pool.key().as_ref()],
+        payer = owner,
+        token::mint = mint,
+        token::authority = order,
+        seeds = [b"order_escrow", order.key().as_ref()],
         bump
     )]
-    pub lp_token_mint: Account<'info, Mint>,
+    pub escrow_account: Account<'info, TokenAccount>,
+
+    /// CHECK: read by close orders only, open orders place against a position that doesn't exist yet
+    #[account(
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[side as u8],
+            &position_index.to_le_bytes()
+        ],
+        bump
+    )]
+    pub position: UncheckedAccount<'info>,
 
     #[account(
-        mut,
         seeds = [b"perpetuals"],
-        bump = perpetuals.bump,
-        constraint = perpetuals.admin_authority == authority.key()
+        bump = perpetuals.bump
     )]
     pub perpetuals: Account<'info, Perpetuals>,
-    
+
+    #[account(
+        seeds = [b"pool", pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub funding_account: Account<'info, TokenAccount>,
+
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct AddCustody<'info> {
+pub struct ExecuteOrder<'info> {
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub keeper: Signer<'info>,
+
+    #[account(mut)]
+    pub owner: SystemAccount<'info>,
 
     #[account(
-        init,
-        payer = authority,
-        space = 8 + Custody::INIT_SPACE,
-        seeds = [b"custody", pool.key().as_ref(), custody_token_mint.key().as_ref()],
+        mut,
+        seeds = [
+            b"order",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &order.index.to_le_bytes()
+        ],
+        bump = order.bump,
+        has_one = owner,
+        close = owner
+    )]
+    pub order: Account<'info, Order>,
+
+    #[account(
+        mut,
+        seeds = [b"order_escrow", order.key().as_ref()],
+        bump = order.escrow_bump
+    )]
+    pub escrow_account: Account<'info, TokenAccount>,
+
+    // Opened here for open orders, the keeper's rent is refunded from Order::rent_deposit
+    #[account(
+        init_if_needed,
+        payer = keeper,
+        space = 8 + Position::INIT_SPACE,
+        seeds = [
+            b"position",
+            owner.key().as_ref(),
+            pool.key().as_ref(),
+            custody.key().as_ref(),
+            &[order.side as u8],
+            &order.position_index.to_le_bytes()
+        ],
         bump
     )]
-    pub custody: Account<'info, Custody>,
+    pub position: Account<'info, Position>,
 
-    pub custody_token_mint: Account<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = keeper,
+        space = 8 + PositionRegistry::INIT_SPACE,
+        seeds = [b"position_registry", owner.key().as_ref()],
+        bump
+    )]
+    pub position_registry: Account<'info, PositionRegistry>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.bump
+    )]
+    pub perpetuals: Account<'info, Perpetuals>,
 
     #[account(
-        mut,
         seeds = [b"pool", pool.name.as_bytes()],
         bump = pool.bump
     )]
     pub pool: Account<'info, Pool>,
 
-    #[account(
-        seeds = [b"perpetuals"],
-        bump = perpetuals.bump,
-        constraint = perpetuals.admin_authority == authority.key()
-    )]
-    pub perpetuals: Account<'info, Perpetuals>,
+    #[account(
+        mut,
+        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Account<'info, Custody>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
+        bump = custody.token_account_bump
+    )]
+    pub custody_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner
+    )]
+    pub owner_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = mint
+    )]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Oracle account validation happens in instruction
+    pub oracle_account: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"order",
+            owner.key().as_ref(),
+            order.pool.as_ref(),
+            order.custody.as_ref(),
+            &order.index.to_le_bytes()
+        ],
+        bump = order.bump,
+        has_one = owner,
+        close = owner
+    )]
+    pub order: Account<'info, Order>,
+
+    #[account(
+        mut,
+        seeds = [b"order_escrow", order.key().as_ref()],
+        bump = order.escrow_bump
+    )]
+    pub escrow_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = escrow_account.mint,
+        token::authority = owner
+    )]
+    pub receiving_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// Account Data Structures
+#[account]
+#[derive(InitSpace)]
+pub struct Perpetuals {
+    pub min_signatures: u8,
+    #[max_len(5)]
+    pub admins: Vec<Pubkey>,
+    #[max_len(10)]
+    pub pools: Vec<Pubkey>,
+    pub permissions: Permissions,
+    pub fee_receiver: Pubkey, // treasury owner that withdraw_fees pays out to
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Multisig {
+    pub instruction_hash: [u8; 32],
+    #[max_len(5)]
+    pub signers: Vec<Pubkey>, // admins that approved instruction_hash
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Permissions {
+    pub allow_swap: bool,
+    pub allow_add_liquidity: bool,
+    pub allow_remove_liquidity: bool,
+    pub allow_open_position: bool,
+    pub allow_close_position: bool,
+    pub allow_pnl_withdrawal: bool,
+    pub allow_collateral_withdrawal: bool,
+    pub allow_size_change: bool,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Pool {
+    #[max_len(64)]
+    pub name: String,
+    #[max_len(10)]
+    pub custodies: Vec<Pubkey>,
+    pub aum_usd: u64,
+    pub permissions: Option<Permissions>, // further restricts the global permissions when set
+    pub bump: u8,
+    pub lp_token_bump: u8,
+    pub inception_time: i64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Custody {
+    pub pool: Pubkey,
+    pub mint: Pubkey,
+    pub decimals: u8,
+    pub is_stable: bool,
+    pub oracle: Pubkey,
+    pub oracle_type: OracleType,
+    pub permissions: Option<Permissions>, // further restricts the pool and global permissions when set
+    pub pricing: PricingParams,
+    pub fees: Fees,
+    pub borrow_rate: BorrowRateParams,
+    pub borrow_rate_state: BorrowRateState,
+    pub funding_rate_state: FundingRateState,
+    pub assets: Assets,
+    pub volume_stats: VolumeStats,
+    pub trade_stats: TradeStats,
+    #[max_len(64)]
+    pub feed_id: Option<String>,
+    pub trading_halted: bool, // set by the update_price circuit breaker
+    pub bump: u8,
+    pub token_account_bump: u8,
+}
+
+// Price account for OracleType::Custom, at [b"oracle_account", pool, mint]
+#[account]
+#[derive(InitSpace)]
+pub struct CustomOracle {
+    pub price: u64,
+    pub expo: i32,
+    pub conf: u64,
+    pub ema: u64,
+    pub publish_time: i64,
+}
 
-    #[account(
-        init,
-        payer = authority,
-        token::mint = custody_token_mint,
-        token::authority = custody,  // FIX: custody is the authority
-        seeds = [b"custody_token_account", pool.key().as_ref(), custody_token_mint.key().as_ref()],
-        bump
-    )]
-    pub custody_token_account: Account<'info, TokenAccount>,
+// Spot and EMA price as read from a custody's oracle, in PRICE_PRECISION
+#[derive(Clone, Copy)]
+pub struct OraclePrice {
+    pub price: u64,
+    pub ema_price: u64,
+}
 
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct PricingParams {
+    pub use_ema: bool,
+    pub use_unrealized_pnl_in_aum: bool,
+    pub trade_spread_long: u64,
+    pub trade_spread_short: u64,
+    pub swap_spread: u64,
+    pub max_leverage: u64, // BPS, 10_000 = 1x
+    pub max_global_short_size_usd: u64,
+    pub max_global_long_size_usd: u64,
+    pub max_funding_rate: u64,
+    pub max_conf_bps: u64, // max oracle confidence interval as bps of price
+    pub max_price_age_sec: u64,
+    pub max_price_deviation_bps: u64, // max move per update_price before trading halts
+    pub current_price: u64,
+    pub ema_price: u64,
+    pub last_update_time: i64,
 }
 
-#[derive(Accounts)]
-pub struct AddLiquidity<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Fees {
+    pub swap_in: u64,
+    pub swap_out: u64,
+    pub stable_swap_in: u64,
+    pub stable_swap_out: u64,
+    pub add_liquidity: u64,
+    pub remove_liquidity: u64,
+    pub open_position: u64,
+    pub close_position: u64,
+    pub liquidation: u64,
+    pub protocol_share: u64,
+}
 
-    #[account(
-        seeds = [b"perpetuals"],
-        bump = perpetuals.bump
-    )]
-    pub perpetuals: Account<'info, Perpetuals>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct BorrowRateParams {
+    pub base_rate: u64,
+    pub slope1: u64,
+    pub slope2: u64,
+    pub optimal_utilization: u64,
+}
 
-    #[account(
-        seeds = [b"pool", pool.name.as_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct BorrowRateState {
+    pub current_rate: u64,
+    pub cumulative_interest: u128, // sum of rate * seconds, at RATE_PRECISION
+    pub last_update: i64,
+}
 
-    #[account(
-        mut,
-        seeds = [b"custody", pool.key().as_ref(), custody_token_mint.key().as_ref()],
-        bump = custody.bump
-    )]
-    pub custody: Account<'info, Custody>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct FundingRateState {
+    pub current_rate: i64, // positive when longs pay shorts
+    pub cumulative_long: i128, // sum of rate * seconds paid per unit of long size
+    pub cumulative_short: i128, // sum of rate * seconds paid per unit of short size
+    pub last_update: i64,
+}
 
-    pub custody_token_mint: Account<'info, Mint>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Assets {
+    pub collateral: u64,
+    pub protocol_fees: u64,
+    pub owned: u64,
+    pub locked: u64,
+}
 
-    #[account(
-        mut,
-        seeds = [b"lp_token_mint", pool.key().as_ref()],
-        bump = pool.lp_token_bump
-    )]
-    pub lp_token_mint: Account<'info, Mint>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct VolumeStats {
+    pub swap_usd: u128,
+    pub add_liquidity_usd: u128,
+    pub remove_liquidity_usd: u128,
+    pub open_position_usd: u128,
+    pub close_position_usd: u128,
+    pub liquidation_usd: u128,
+}
 
-    #[account(
-        mut,
-        token::mint = custody_token_mint,
-        token::authority = owner
-    )]
-    pub funding_account: Account<'info, TokenAccount>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct TradeStats {
+    pub oi_long_usd: u64,
+    pub oi_short_usd: u64,
+    pub total_long_funding: i64,
+    pub total_short_funding: i64,
+    pub long_quantity: u128, // sum of size / entry_price, marks long OI to market
+    pub short_quantity: u128, // sum of size / entry_price, marks short OI to market
+}
 
-    #[account(
-        mut,
-        token::mint = lp_token_mint,
-        token::authority = owner
-    )]
-    pub lp_token_account: Account<'info, TokenAccount>,
+#[account]
+#[derive(InitSpace)]
+pub struct Position {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub custody: Pubkey,
+    pub side: Side,
+    pub index: u64, // user chosen, lets an owner hold several positions per custody and side
+    pub collateral_amount: u64,
+    pub collateral_usd: u64, // collateral value at entry, less fees charged since
+    pub leverage: u64, // BPS, 10_000 = 1x
+    pub size_usd: u64,
+    pub locked_amount: u64, // tokens reserved from the pool for the max payout
+    pub entry_price: u64,
+    pub entry_timestamp: i64,
+    pub unrealized_pnl: i64, // in USD
+    pub liquidation_price: u64, // as of the last open/update, liquidation recomputes it
+    pub cumulative_interest_snapshot: u128,
+    pub funding_snapshot: i128,
+    pub bump: u8,
+}
 
-    #[account(
-        mut,
-        seeds = [b"custody_token_account", pool.key().as_ref(), custody_token_mint.key().as_ref()],
-        bump = custody.token_account_bump
-    )]
-    pub custody_token_account: Account<'info, TokenAccount>,
+// Open positions of an owner, at [b"position_registry", owner]
+#[account]
+#[derive(InitSpace)]
+pub struct PositionRegistry {
+    pub owner: Pubkey,
+    #[max_len(16)]
+    pub positions: Vec<Pubkey>,
+    pub bump: u8,
+}
 
-    pub token_program: Program<'info, Token>,
+// Limit order executed by keepers, at [b"order", owner, pool, custody, index]
+// Funds sit in a token account at [b"order_escrow", order] until execution or cancel
+#[account]
+#[derive(InitSpace)]
+pub struct Order {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub custody: Pubkey,
+    pub order_type: OrderType,
+    pub side: Side,
+    pub position_index: u64, // position opened or closed by this order
+    pub collateral_amount: u64, // open orders only
+    pub leverage: u64, // open orders only, BPS
+    pub trigger_price: u64, // limit on the entry/exit price, PRICE_PRECISION
+    pub execution_fee: u64, // in custody tokens, paid to the keeper
+    pub rent_deposit: u64, // lamports held on the order to cover rent the keeper pays on execution
+    pub position_entry_timestamp: i64, // close orders only, ties the order to the position it was placed on
+    pub index: u64,
+    pub bump: u8,
+    pub escrow_bump: u8,
 }
 
-#[derive(Accounts)]
-pub struct RemoveLiquidity<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum OrderType {
+    Open,
+    Close, // take profit, fires once the exit price moves past the trigger in the position's favour
+    StopLoss // fires once the exit price moves past the trigger against the position
+}
 
-    #[account(
-        seeds = [b"perpetuals"],
-        bump = perpetuals.bump
-    )]
-    pub perpetuals: Account<'info, Perpetuals>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub enum OracleType {
+    Pyth, 
+    Custom,
+    None
+}
 
-    #[account(
-        seeds = [b"pool", pool.name.as_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum Side {
+    Long, 
+    Short
+}
 
-    #[account(
-        mut,
-        seeds = [b"custody", pool.key().as_ref(), custody_token_mint.key().as_ref()],
-        bump = custody.bump
-    )]
-    pub custody: Account<'info, Custody>,
+// Events
+#[event]
+pub struct CustodyConfigUpdated {
+    pub custody: Pubkey,
+    pub old_is_stable: bool,
+    pub new_is_stable: bool,
+    pub old_oracle: Pubkey,
+    pub new_oracle: Pubkey,
+    pub old_oracle_type: OracleType,
+    pub new_oracle_type: OracleType,
+    pub old_feed_id: Option<String>,
+    pub new_feed_id: Option<String>,
+    pub old_pricing: PricingParams,
+    pub new_pricing: PricingParams,
+    pub old_fees: Fees,
+    pub new_fees: Fees,
+    pub old_borrow_rate: BorrowRateParams,
+    pub new_borrow_rate: BorrowRateParams,
+}
 
-    pub custody_token_mint: Account<'info, Mint>,
+#[event]
+pub struct CircuitBreakerTripped {
+    pub custody: Pubkey,
+    pub current_price: u64,
+    pub rejected_price: u64,
+}
 
-    #[account(
-        mut,
-        seeds = [b"lp_token_mint", pool.key().as_ref()],
-        bump = pool.lp_token_bump
-    )]
-    pub lp_token_mint: Account<'info, Mint>,
+// Helper Functions
+fn validate_admin_signers(admins: &[Pubkey], min_signatures: u8) -> Result<()> {
+    require!(!admins.is_empty() && admins.len() <= MAX_ADMINS, PerpError::InvalidMultisigConfig);
+    require!(
+        min_signatures > 0 && min_signatures as usize <= admins.len(),
+        PerpError::InvalidMultisigConfig
+    );
+
+    for (i, admin) in admins.iter().enumerate() {
+        require!(!admins[i + 1..].contains(admin), PerpError::InvalidMultisigConfig);
+    }
 
-    #[account(
-        mut,
-        token::mint = lp_token_mint,
-        token::authority = owner
-    )]
-    pub lp_token_account: Account<'info, TokenAccount>,
+    Ok(())
+}
 
-    #[account(
-        mut,
-        token::mint = custody_token_mint,
-        token::authority = owner
-    )]
-    pub receiving_account: Account<'info, TokenAccount>,
+fn get_instruction_hash<T: AnchorSerialize>(instruction: &str, accounts: &[Pubkey], args: &T) -> Result<[u8; 32]> {
+    let mut data = Vec::new();
+    args.serialize(&mut data)
+        .map_err(|_| ErrorCode::InstructionDidNotSerialize)?;
 
-    #[account(
-        mut,
-        seeds = [b"custody_token_account", pool.key().as_ref(), custody_token_mint.key().as_ref()],
-        bump = custody.token_account_bump
-    )]
-    pub custody_token_account: Account<'info, TokenAccount>,
+    let mut seeds: Vec<&[u8]> = vec![instruction.as_bytes()];
+    seeds.extend(accounts.iter().map(|key| key.as_ref()));
+    seeds.push(&data);
 
-    pub token_program: Program<'info, Token>,
+    Ok(anchor_lang::solana_program::hash::hashv(&seeds).to_bytes())
 }
 
-#[derive(Accounts)]
-pub struct UpdatePrice<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+// Records the admin's approval and returns how many signatures are still missing.
+// A different instruction hash discards the pending proposal.
+fn sign_multisig(
+    multisig: &mut Multisig,
+    perpetuals: &Perpetuals,
+    signer: &Pubkey,
+    instruction_hash: [u8; 32],
+) -> Result<u8> {
+    require!(perpetuals.admins.contains(signer), PerpError::MultisigAccountNotAuthorized);
+
+    if multisig.instruction_hash != instruction_hash {
+        multisig.instruction_hash = instruction_hash;
+        multisig.signers.clear();
+    }
 
-    #[account(
-        mut,
-        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.bump
-    )]
-    pub custody: Account<'info, Custody>,
+    require!(!multisig.signers.contains(signer), PerpError::MultisigAlreadySigned);
+    multisig.signers.push(*signer);
 
-    #[account(
-        seeds = [b"pool", pool.name.as_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+    let signed = multisig.signers.len() as u8;
+    if signed >= perpetuals.min_signatures {
+        multisig.instruction_hash = [0; 32];
+        multisig.signers.clear();
+        return Ok(0);
+    }
 
-    pub mint: Account<'info, Mint>,
+    let signatures_left = perpetuals.min_signatures - signed;
+    msg!("Instruction signed, {} more signature(s) required", signatures_left);
 
-    #[account(
-        seeds = [b"perpetuals"],
-        bump = perpetuals.bump,
-        constraint = perpetuals.admin_authority == authority.key()
-    )]
-    pub perpetuals: Account<'info, Perpetuals>,
+    Ok(signatures_left)
 }
 
-#[derive(Accounts)]
-pub struct OpenPosition<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+// An action must be allowed globally and by any pool or custody override
+fn check_permission(
+    perpetuals: &Perpetuals,
+    pool: &Pool,
+    custody: &Custody,
+    allowed: fn(&Permissions) -> bool,
+) -> Result<()> {
+    let overrides_allow = [&pool.permissions, &custody.permissions]
+        .iter()
+        .all(|permissions| match permissions {
+            Some(permissions) => allowed(permissions),
+            None => true,
+        });
+
+    require!(allowed(&perpetuals.permissions) && overrides_allow, PerpError::ActionNotAllowed);
+
+    Ok(())
+}
 
-    #[account(
-        init,
-        payer = owner,
-        space = 8 + Position::INIT_SPACE,
-        seeds = [b"position", owner.key().as_ref(), pool.key().as_ref(), custody.key().as_ref()],
-        bump
-    )]
-    pub position: Account<'info, Position>,
+// Pyth custodies need a price account and feed id, custom oracles need an account
+fn validate_oracle_config(oracle: Pubkey, oracle_type: &OracleType, feed_id: &Option<String>) -> Result<()> {
+    if let Some(feed_id) = feed_id {
+        require!(feed_id.len() <= 64, PerpError::InvalidOracleConfig);
+        get_feed_id_from_hex(feed_id).map_err(|_| PerpError::InvalidOracleConfig)?;
+    }
+
+    match oracle_type {
+        OracleType::Pyth => {
+            require!(oracle != Pubkey::default() && feed_id.is_some(), PerpError::InvalidOracleConfig);
+        },
+        OracleType::Custom => {
+            require!(oracle != Pubkey::default(), PerpError::InvalidOracleConfig);
+        },
+        OracleType::None => {}
+    }
 
-    #[account(
-        seeds = [b"perpetuals"],
-        bump = perpetuals.bump
-    )]
-    pub perpetuals: Account<'info, Perpetuals>,
+    Ok(())
+}
 
-    #[account(
-        seeds = [b"pool", pool.name.as_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+fn validate_custody_config(
+    pricing: &PricingParams,
+    fees: &Fees,
+    borrow_rate: &BorrowRateParams,
+) -> Result<()> {
+    require!(
+        pricing.trade_spread_long < BPS_PRECISION
+            && pricing.trade_spread_short < BPS_PRECISION
+            && pricing.swap_spread < BPS_PRECISION
+            && pricing.max_leverage >= BPS_PRECISION
+            && pricing.max_leverage <= MAX_LEVERAGE
+            && pricing.max_global_long_size_usd <= MAX_OPEN_INTEREST_USD
+            && pricing.max_global_short_size_usd <= MAX_OPEN_INTEREST_USD
+            && pricing.max_funding_rate <= RATE_PRECISION
+            && pricing.max_conf_bps > 0
+            && pricing.max_conf_bps <= BPS_PRECISION
+            && pricing.max_price_age_sec > 0
+            && pricing.max_price_deviation_bps > 0
+            && pricing.max_price_deviation_bps <= BPS_PRECISION,
+        PerpError::InvalidPricingConfig
+    );
+
+    require!(
+        fees.swap_in <= BPS_PRECISION
+            && fees.swap_out <= BPS_PRECISION
+            && fees.stable_swap_in <= BPS_PRECISION
+            && fees.stable_swap_out <= BPS_PRECISION
+            && fees.add_liquidity <= BPS_PRECISION
+            && fees.remove_liquidity <= BPS_PRECISION
+            && fees.open_position <= BPS_PRECISION
+            && fees.close_position <= BPS_PRECISION
+            && fees.liquidation <= BPS_PRECISION
+            && fees.protocol_share <= BPS_PRECISION,
+        PerpError::InvalidFeeConfig
+    );
+
+    // At max leverage the liquidation margin must still cover the close and liquidation fees
+    let margin_bps = (LIQUIDATION_THRESHOLD as u128)
+        .checked_mul(BPS_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(pricing.max_leverage as u128)
+        .ok_or(PerpError::MathOverflow)?;
+    require!(
+        margin_bps > (fees.close_position as u128) + (fees.liquidation as u128),
+        PerpError::InvalidPricingConfig
+    );
+
+    require!(
+        borrow_rate.optimal_utilization > 0
+            && borrow_rate.optimal_utilization <= RATE_PRECISION
+            && borrow_rate.base_rate <= MAX_BORROW_RATE
+            && borrow_rate.slope1 <= MAX_BORROW_RATE
+            && borrow_rate.slope2 <= MAX_BORROW_RATE,
+        PerpError::InvalidBorrowRateConfig
+    );
+
+    Ok(())
+}
 
-    #[account(
-        mut,
-        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.bump
-    )]
-    pub custody: Account<'info, Custody>,
+// remaining_accounts: every custody in Pool::custodies order, followed by their oracle accounts
+fn calculate_pool_value(pool: &Pool, accounts: &[AccountInfo], clock: &Clock) -> Result<u64> {
+    let custodies_len = pool.custodies.len();
+    require!(accounts.len() == custodies_len * 2, PerpError::InvalidPoolAccounts);
 
-    pub mint: Account<'info, Mint>,
+    let mut total_value = 0i128;
 
-    #[account(
-        mut,
-        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.token_account_bump
-    )]
-    pub custody_token_account: Account<'info, TokenAccount>,
+    for (i, custody_key) in pool.custodies.iter().enumerate() {
+        let custody_info = &accounts[i];
+        require_keys_eq!(custody_info.key(), *custody_key, PerpError::InvalidPoolAccounts);
+        require_keys_eq!(*custody_info.owner, crate::ID, PerpError::InvalidPoolAccounts);
 
-    #[account(
-        mut,
-        token::mint = mint,
-        token::authority = owner
-    )]
-    pub collateral_account: Account<'info, TokenAccount>,
+        let custody = Custody::try_deserialize(&mut &custody_info.try_borrow_data()?[..])?;
+        let oracle_account = &accounts[custodies_len + i];
+        let price = get_aum_price(&custody, oracle_account, clock)?;
 
-    /// CHECK: Oracle account validation happens in instruction
-    pub oracle_account: AccountInfo<'info>,
+        let custody_value = token_amount_to_usd(custody.assets.owned, custody.decimals, price)?;
+        total_value = total_value
+            .checked_add(custody_value as i128)
+            .ok_or(PerpError::MathOverflow)?;
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+        // traders' unrealized profit is owed by the pool, their losses accrue to it
+        if custody.pricing.use_unrealized_pnl_in_aum {
+            let traders_pnl_usd = calculate_unrealized_pnl(&custody, price)?;
+            total_value = total_value
+                .checked_sub(traders_pnl_usd as i128)
+                .ok_or(PerpError::MathOverflow)?;
+        }
+    }
+
+    total_value
+        .max(0)
+        .try_into()
+        .map_err(|_| PerpError::MathOverflow.into())
 }
 
-#[derive(Accounts)]
-pub struct ClosePosition<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+// Net USD PnL of all open positions in the custody, marked at price
+fn calculate_unrealized_pnl(custody: &Custody, price: u64) -> Result<i64> {
+    let long_value = custody.trade_stats.long_quantity
+        .checked_mul(price as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(PRICE_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)? as i128;
+    let short_value = custody.trade_stats.short_quantity
+        .checked_mul(price as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(PRICE_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)? as i128;
 
-    #[account(
-        mut,
-        seeds = [b"position", owner.key().as_ref(), pool.key().as_ref(), custody.key().as_ref()],
-        bump = position.bump,
-        has_one = owner,
-        close = owner
-    )]
-    pub position: Account<'info, Position>,
+    let long_pnl = long_value - custody.trade_stats.oi_long_usd as i128;
+    let short_pnl = custody.trade_stats.oi_short_usd as i128 - short_value;
 
-    #[account(
-        seeds = [b"perpetuals"],
-        bump = perpetuals.bump
-    )]
-    pub perpetuals: Account<'info, Perpetuals>,
+    long_pnl
+        .checked_add(short_pnl)
+        .ok_or(PerpError::MathOverflow)?
+        .try_into()
+        .map_err(|_| PerpError::MathOverflow.into())
+}
 
-    #[account(
-        seeds = [b"pool", pool.name.as_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+fn get_aum_price(custody: &Custody, oracle_account: &AccountInfo, clock: &Clock) -> Result<u64> {
+    let oracle_price = get_oracle_price(custody, oracle_account, clock)?;
 
-    #[account(
-        mut,
-        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.bump
-    )]
-    pub custody: Account<'info, Custody>,
+    if custody.pricing.use_ema {
+        return Ok(oracle_price.ema_price);
+    }
 
-    pub mint: Account<'info, Mint>,
+    Ok(oracle_price.price)
+}
 
-    #[account(
-        mut,
-        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.token_account_bump
-    )]
-    pub custody_token_account: Account<'info, TokenAccount>,
+// With use_ema, trades take whichever of spot and EMA is worse for the trader
+fn get_min_price(custody: &Custody, oracle_price: &OraclePrice) -> u64 {
+    if custody.pricing.use_ema {
+        oracle_price.price.min(oracle_price.ema_price)
+    } else {
+        oracle_price.price
+    }
+}
 
-    #[account(
-        mut,
-        token::mint = mint,
-        token::authority = owner
-    )]
-    pub receiving_account: Account<'info, TokenAccount>,
+fn get_max_price(custody: &Custody, oracle_price: &OraclePrice) -> u64 {
+    if custody.pricing.use_ema {
+        oracle_price.price.max(oracle_price.ema_price)
+    } else {
+        oracle_price.price
+    }
+}
 
-    /// CHECK: Oracle account validation happens in instruction
-    pub oracle_account: AccountInfo<'info>,
+// Longs enter above and exit below the oracle by trade_spread_long, shorts the reverse.
+// The spread lowers trader PnL, so it ends up with the pool as LP revenue
+fn get_entry_price(custody: &Custody, oracle_price: &OraclePrice, side: &Side) -> Result<u64> {
+    match side {
+        Side::Long => apply_spread(get_max_price(custody, oracle_price), custody.pricing.trade_spread_long, true),
+        Side::Short => apply_spread(get_min_price(custody, oracle_price), custody.pricing.trade_spread_short, false),
+    }
+}
 
-    pub token_program: Program<'info, Token>,
+fn get_exit_price(custody: &Custody, oracle_price: &OraclePrice, side: &Side) -> Result<u64> {
+    match side {
+        Side::Long => apply_spread(get_min_price(custody, oracle_price), custody.pricing.trade_spread_long, false),
+        Side::Short => apply_spread(get_max_price(custody, oracle_price), custody.pricing.trade_spread_short, true),
+    }
 }
 
-#[derive(Accounts)]
-pub struct LiquidatePosition<'info> {
-    #[account(mut)]
-    pub liquidator: Signer<'info>,
+fn position_quantity(size_usd: u64, entry_price: u64) -> Result<u128> {
+    (size_usd as u128)
+        .checked_mul(PRICE_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(entry_price as u128)
+        .ok_or(PerpError::MathOverflow.into())
+}
 
-    #[account(
-        mut,
-        seeds = [b"position", position.owner.key().as_ref(), pool.key().as_ref(), custody.key().as_ref()],
-        bump = position.bump,
-        close = liquidator
-    )]
-    pub position: Account<'info, Position>,
+fn add_open_interest(custody: &mut Custody, side: &Side, size_usd: u64, entry_price: u64) -> Result<()> {
+    let quantity = position_quantity(size_usd, entry_price)?;
+    let trade_stats = &mut custody.trade_stats;
 
-    #[account(
-        seeds = [b"pool", pool.name.as_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+    match side {
+        Side::Long => {
+            trade_stats.oi_long_usd = trade_stats.oi_long_usd
+                .checked_add(size_usd)
+                .ok_or(PerpError::MathOverflow)?;
+            trade_stats.long_quantity = trade_stats.long_quantity
+                .checked_add(quantity)
+                .ok_or(PerpError::MathOverflow)?;
+        },
+        Side::Short => {
+            trade_stats.oi_short_usd = trade_stats.oi_short_usd
+                .checked_add(size_usd)
+                .ok_or(PerpError::MathOverflow)?;
+            trade_stats.short_quantity = trade_stats.short_quantity
+                .checked_add(quantity)
+                .ok_or(PerpError::MathOverflow)?;
+        }
+    }
 
-    #[account(
-        mut,
-        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.bump
-    )]
-    pub custody: Account<'info, Custody>,
+    Ok(())
+}
 
-    pub mint: Account<'info, Mint>,
+fn remove_open_interest(custody: &mut Custody, side: &Side, size_usd: u64, entry_price: u64) -> Result<()> {
+    let quantity = position_quantity(size_usd, entry_price)?;
+    let trade_stats = &mut custody.trade_stats;
 
-    #[account(
-        mut,
-        seeds = [b"custody_token_account", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.token_account_bump
-    )]
-    pub custody_token_account: Account<'info, TokenAccount>,
+    match side {
+        Side::Long => {
+            trade_stats.oi_long_usd = trade_stats.oi_long_usd.saturating_sub(size_usd);
+            trade_stats.long_quantity = trade_stats.long_quantity.saturating_sub(quantity);
+        },
+        Side::Short => {
+            trade_stats.oi_short_usd = trade_stats.oi_short_usd.saturating_sub(size_usd);
+            trade_stats.short_quantity = trade_stats.short_quantity.saturating_sub(quantity);
+        }
+    }
 
-    #[account(
-        mut,
-        token::mint = mint,
-        token::authority = liquidator
-    )]
-    pub liquidator_account: Account<'info, TokenAccount>,
+    Ok(())
+}
 
-    #[account(
-        mut,
-        token::mint = mint,
-        token::authority = position.owner
-    )]
-    pub position_owner_account: Account<'info, TokenAccount>,
+// size = collateral * leverage, with leverage in BPS
+fn leveraged_size(collateral_usd: u64, leverage: u64) -> Result<u64> {
+    let size = (collateral_usd as u128)
+        .checked_mul(leverage as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(BPS_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?;
 
-    /// CHECK: Oracle account validation happens in instruction
-    pub oracle_account: AccountInfo<'info>,
+    size.try_into().map_err(|_| PerpError::MathOverflow.into())
+}
 
-    pub token_program: Program<'info, Token>,
+// Books a new position at the entry price and returns the opening fee the owner owes in tokens
+fn book_open_position(
+    position: &mut Position,
+    custody: &mut Custody,
+    side: Side,
+    collateral_amount: u64,
+    leverage: u64,
+    oracle_price: &OraclePrice,
+    now: i64,
+) -> Result<u64> {
+    check_leverage(custody, leverage)?;
+
+    let entry_price = get_entry_price(custody, oracle_price, &side)?;
+    let exit_price = get_exit_price(custody, oracle_price, &side)?;
+
+    // Collateral is valued at the lower price, as in add_collateral; size is in USD
+    let collateral_price = get_min_price(custody, oracle_price);
+    let collateral_usd = token_amount_to_usd(collateral_amount, custody.decimals, collateral_price)?;
+    let size_usd = leveraged_size(collateral_usd, leverage)?;
+
+    check_open_interest_limits(custody, &side, size_usd)?;
+
+    let opening_fee = usd_to_token_amount(
+        calculate_fee(size_usd, custody.fees.open_position)?,
+        custody.decimals,
+        entry_price
+    )?;
+
+    // Reserve the max payout so LP withdrawals can't drain it
+    let locked_amount = usd_to_token_amount(size_usd, custody.decimals, entry_price)?;
+    require!(available_liquidity(custody) >= locked_amount, PerpError::InsufficientLiquidity);
+
+    custody.assets.collateral = custody.assets.collateral
+        .checked_add(collateral_amount)
+        .ok_or(PerpError::MathOverflow)?;
+    collect_fee(custody, opening_fee)?;
+    custody.assets.locked = custody.assets.locked
+        .checked_add(locked_amount)
+        .ok_or(PerpError::MathOverflow)?;
+    add_open_interest(custody, &side, size_usd, entry_price)?;
+
+    position.side = side;
+    position.collateral_amount = collateral_amount;
+    position.collateral_usd = collateral_usd;
+    position.leverage = leverage;
+    position.size_usd = size_usd;
+    position.locked_amount = locked_amount;
+    position.entry_price = entry_price;
+    position.entry_timestamp = now;
+    position.unrealized_pnl = 0;
+    position.cumulative_interest_snapshot = custody.borrow_rate_state.cumulative_interest;
+    position.funding_snapshot = get_cumulative_funding(custody, &side);
+    position.liquidation_price = calculate_liquidation_price(position, custody, LIQUIDATION_THRESHOLD)?;
+
+    // Spread and fees must not leave the new position liquidatable on the spot
+    check_clear_of_liquidation(position, exit_price)?;
+
+    Ok(opening_fee)
 }
 
-#[derive(Accounts)]
-pub struct UpdatePosition<'info> {
-    #[account(
-        mut,
-        seeds = [b"position", position.owner.key().as_ref(), pool.key().as_ref(), custody.key().as_ref()],
-        bump = position.bump
-    )]
-    pub position: Account<'info, Position>,
+// Rejects positions whose exit price is already at or past the liquidation price
+fn check_clear_of_liquidation(position: &Position, exit_price: u64) -> Result<()> {
+    let clear = match position.side {
+        Side::Long => exit_price > position.liquidation_price,
+        Side::Short => exit_price < position.liquidation_price,
+    };
+    require!(clear, PerpError::InsufficientCollateral);
 
-    #[account(
-        seeds = [b"pool", pool.name.as_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+    Ok(())
+}
 
-    #[account(
-        seeds = [b"custody", pool.key().as_ref(), mint.key().as_ref()],
-        bump = custody.bump
-    )]
-    pub custody: Account<'info, Custody>,
+// Settles a full close at exit_price and returns the tokens owed to the owner
+fn book_close_position(position: &Position, custody: &mut Custody, exit_price: u64) -> Result<u64> {
+    // PnL, fees and funding accrue in USD and settle in tokens at the exit price
+    let pnl = signed_usd_to_token_amount(calculate_pnl(position, exit_price)?, custody.decimals, exit_price)?;
+    let closing_fee = usd_to_token_amount(
+        calculate_fee(position.size_usd, custody.fees.close_position)?,
+        custody.decimals,
+        exit_price
+    )?;
+    let borrow_fee = usd_to_token_amount(calculate_borrow_fee(position, custody)?, custody.decimals, exit_price)?;
+    let funding_payment = signed_usd_to_token_amount(
+        calculate_funding_payment(position, custody)?,
+        custody.decimals,
+        exit_price
+    )?;
+
+    let mut transfer_amount = position.collateral_amount;
+
+    // Profit is paid out of the liquidity locked for this position, losses go to the pool
+    let pnl_settled = if pnl >= 0 {
+        let profit = (pnl as u64).min(position.locked_amount);
+        transfer_amount = transfer_amount
+            .checked_add(profit)
+            .ok_or(PerpError::MathOverflow)?;
+        profit as i64
+    } else {
+        let loss = pnl.unsigned_abs().min(transfer_amount);
+        transfer_amount -= loss;
+        -(loss as i64)
+    };
 
-    pub mint: Account<'info, Mint>,
+    let closing_fee = closing_fee.min(transfer_amount);
+    transfer_amount -= closing_fee;
+    let borrow_fee = borrow_fee.min(transfer_amount);
+    transfer_amount -= borrow_fee;
 
-    /// CHECK: Oracle account validation happens in instruction
-    pub oracle_account: AccountInfo<'info>,
+    // Settle funding: payments go to the pool, receipts come out of it
+    let funding_settled = if funding_payment >= 0 {
+        let paid = (funding_payment as u64).min(transfer_amount);
+        transfer_amount -= paid;
+        paid as i64
+    } else {
+        transfer_amount = transfer_amount
+            .checked_add(funding_payment.unsigned_abs())
+            .ok_or(PerpError::MathOverflow)?;
+        funding_payment
+    };
+
+    custody.assets.collateral = custody.assets.collateral.saturating_sub(position.collateral_amount);
+    collect_fee(custody, closing_fee)?;
+    collect_fee(custody, borrow_fee)?;
+    custody.assets.locked = custody.assets.locked.saturating_sub(position.locked_amount);
+    settle_pool_owned(custody, -pnl_settled)?;
+    settle_pool_owned(custody, funding_settled)?;
+    remove_open_interest(custody, &position.side, position.size_usd, position.entry_price)?;
+
+    Ok(transfer_amount)
 }
 
-// Account Data Structures
-#[account]
-#[derive(InitSpace)]
-pub struct Perpetuals {
-    pub admin_authority: Pubkey,
-    pub min_signatures: u8,
-    #[max_len(5)]
-    pub admins: Vec<Pubkey>,
-    #[max_len(10)]
-    pub pools: Vec<Pubkey>,
-    pub permissions: Permissions,
-    pub bump: u8,
+// Buying orders (open long, close short) fill at or below the trigger, selling orders at or above
+fn order_triggered(order: &Order, price: u64) -> bool {
+    match (order.order_type, order.side) {
+        (OrderType::Open, Side::Long)
+        | (OrderType::Close, Side::Short)
+        | (OrderType::StopLoss, Side::Long) => price <= order.trigger_price,
+        (OrderType::Open, Side::Short)
+        | (OrderType::Close, Side::Long)
+        | (OrderType::StopLoss, Side::Short) => price >= order.trigger_price,
+    }
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct Permissions {
-    pub allow_swap: bool,
-    pub allow_add_liquidity: bool,
-    pub allow_remove_liquidity: bool,
-    pub allow_open_position: bool,
-    pub allow_close_position: bool,
-    pub allow_pnl_withdrawal: bool,
-    pub allow_collateral_withdrawal: bool,
-    pub allow_size_change: bool,
+// Opening fee in tokens is price independent, +1 covers rounding in the USD conversion
+fn max_opening_fee(custody: &Custody, collateral_amount: u64, leverage: u64) -> Result<u64> {
+    calculate_fee(leveraged_size(collateral_amount, leverage)?, custody.fees.open_position)?
+        .checked_add(1)
+        .ok_or(PerpError::MathOverflow.into())
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Pool {
-    #[max_len(64)]
-    pub name: String,
-    #[max_len(10)]
-    pub custodies: Vec<Pubkey>,
-    pub aum_usd: u64,
-    pub bump: u8,
-    pub lp_token_bump: u8,
-    pub inception_time: i64,
+// size / collateral in BPS
+fn calculate_leverage(size_usd: u64, collateral_usd: u64) -> Result<u64> {
+    require!(collateral_usd > 0, PerpError::InvalidCollateralAmount);
+
+    let leverage = (size_usd as u128)
+        .checked_mul(BPS_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(collateral_usd as u128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    leverage.try_into().map_err(|_| PerpError::MathOverflow.into())
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Custody {
-    pub pool: Pubkey,
-    pub mint: Pubkey,
-    pub decimals: u8,
-    pub is_stable: bool,
-    pub oracle: Pubkey,
-    pub oracle_type: OracleType,
-    pub pricing: PricingParams,
-    pub fees: Fees,
-    pub borrow_rate: BorrowRateParams,
-    pub assets: Assets,
-    pub volume_stats: VolumeStats,
-    pub trade_stats: TradeStats,
-    #[max_len(64)]
-    pub feed_id: Option<String>,
-    pub bump: u8,
-    pub token_account_bump: u8,
+// amount * part / total, for releasing a share of a position
+fn proportional_amount(amount: u64, part: u64, total: u64) -> Result<u64> {
+    let share = (amount as u128)
+        .checked_mul(part as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(total as u128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    share.try_into().map_err(|_| PerpError::MathOverflow.into())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct PricingParams {
-    pub use_ema: bool,
-    pub use_unrealized_pnl_in_aum: bool,
-    pub trade_spread_long: u64,
-    pub trade_spread_short: u64,
-    pub swap_spread: u64,
-    pub max_leverage: u64,
-    pub max_global_short_size_usd: u64,
-    pub max_global_long_size_usd: u64,
-    pub current_price: u64,
-    pub ema_price: u64,
-    pub last_update_time: i64,
+// Charges accrued borrow and funding against the position's collateral and resets its snapshots
+fn settle_position_costs(position: &mut Position, custody: &mut Custody, price: u64) -> Result<()> {
+    let borrow_fee_usd = calculate_borrow_fee(position, custody)?;
+    let borrow_fee = usd_to_token_amount(borrow_fee_usd, custody.decimals, price)?
+        .min(position.collateral_amount);
+    position.collateral_amount -= borrow_fee;
+    position.collateral_usd = position.collateral_usd.saturating_sub(borrow_fee_usd);
+    custody.assets.collateral = custody.assets.collateral.saturating_sub(borrow_fee);
+    collect_fee(custody, borrow_fee)?;
+
+    let funding_usd = calculate_funding_payment(position, custody)?;
+    let funding = signed_usd_to_token_amount(funding_usd, custody.decimals, price)?;
+    let funding_settled = if funding >= 0 {
+        let paid = (funding as u64).min(position.collateral_amount);
+        position.collateral_amount -= paid;
+        position.collateral_usd = position.collateral_usd.saturating_sub(funding_usd as u64);
+        custody.assets.collateral = custody.assets.collateral.saturating_sub(paid);
+        paid as i64
+    } else {
+        let received = funding.unsigned_abs();
+        position.collateral_amount = position.collateral_amount
+            .checked_add(received)
+            .ok_or(PerpError::MathOverflow)?;
+        position.collateral_usd = position.collateral_usd
+            .checked_add(funding_usd.unsigned_abs())
+            .ok_or(PerpError::MathOverflow)?;
+        custody.assets.collateral = custody.assets.collateral
+            .checked_add(received)
+            .ok_or(PerpError::MathOverflow)?;
+        funding
+    };
+    settle_pool_owned(custody, funding_settled)?;
+
+    position.cumulative_interest_snapshot = custody.borrow_rate_state.cumulative_interest;
+    position.funding_snapshot = get_cumulative_funding(custody, &position.side);
+
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct Fees {
-    pub swap_in: u64,
-    pub swap_out: u64,
-    pub stable_swap_in: u64,
-    pub stable_swap_out: u64,
-    pub add_liquidity: u64,
-    pub remove_liquidity: u64,
-    pub open_position: u64,
-    pub close_position: u64,
-    pub liquidation: u64,
-    pub protocol_share: u64,
+fn check_leverage(custody: &Custody, leverage: u64) -> Result<()> {
+    require!(leverage <= custody.pricing.max_leverage, PerpError::MaxLeverageExceeded);
+
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct BorrowRateParams {
-    pub base_rate: u64,
-    pub slope1: u64,
-    pub slope2: u64,
-    pub optimal_utilization: u64,
+// Rejects size increases that would push the side's OI past the custody cap
+fn check_open_interest_limits(custody: &Custody, side: &Side, size_usd: u64) -> Result<()> {
+    match side {
+        Side::Long => {
+            let oi_long = custody.trade_stats.oi_long_usd
+                .checked_add(size_usd)
+                .ok_or(PerpError::MathOverflow)?;
+            require!(oi_long <= custody.pricing.max_global_long_size_usd, PerpError::MaxGlobalLongSizeExceeded);
+        },
+        Side::Short => {
+            let oi_short = custody.trade_stats.oi_short_usd
+                .checked_add(size_usd)
+                .ok_or(PerpError::MathOverflow)?;
+            require!(oi_short <= custody.pricing.max_global_short_size_usd, PerpError::MaxGlobalShortSizeExceeded);
+        }
+    }
+
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct Assets {
-    pub collateral: u64,
-    pub protocol_fees: u64,
-    pub owned: u64,
-    pub locked: u64,
+// Pool liquidity not reserved for open positions
+fn available_liquidity(custody: &Custody) -> u64 {
+    custody.assets.owned.saturating_sub(custody.assets.locked)
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct VolumeStats {
-    pub swap_usd: u128,
-    pub add_liquidity_usd: u128,
-    pub remove_liquidity_usd: u128,
-    pub open_position_usd: u128,
-    pub close_position_usd: u128,
-    pub liquidation_usd: u128,
+fn calculate_fee(amount: u64, fee_bps: u64) -> Result<u64> {
+    let fee = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(BPS_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    fee.try_into().map_err(|_| PerpError::MathOverflow.into())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct TradeStats {
-    pub oi_long_usd: u64,
-    pub oi_short_usd: u64,
-    pub total_long_funding: i64,
-    pub total_short_funding: i64,
+// protocol_share of each fee goes to the protocol, the rest accrues to LPs
+fn collect_fee(custody: &mut Custody, fee: u64) -> Result<()> {
+    let protocol_fee = calculate_fee(fee, custody.fees.protocol_share)?;
+    let lp_fee = fee
+        .checked_sub(protocol_fee)
+        .ok_or(PerpError::MathOverflow)?;
+
+    custody.assets.protocol_fees = custody.assets.protocol_fees
+        .checked_add(protocol_fee)
+        .ok_or(PerpError::MathOverflow)?;
+    custody.assets.owned = custody.assets.owned
+        .checked_add(lp_fee)
+        .ok_or(PerpError::MathOverflow)?;
+
+    Ok(())
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Position {
-    pub owner: Pubkey,
-    pub pool: Pubkey,
-    pub custody: Pubkey,
-    pub side: Side,
-    pub collateral_amount: u64,
-    pub leverage: u64,
-    pub size_usd: u64,
-    pub entry_price: u64,
-    pub entry_timestamp: i64,
-    pub unrealized_pnl: i64,
-    pub bump: u8,
+// Moves price against the trader by spread_bps (up if increase, down otherwise)
+fn apply_spread(price: u64, spread_bps: u64, increase: bool) -> Result<u64> {
+    let multiplier = if increase {
+        BPS_PRECISION.checked_add(spread_bps).ok_or(PerpError::MathOverflow)?
+    } else {
+        BPS_PRECISION.checked_sub(spread_bps).ok_or(PerpError::MathOverflow)?
+    };
+
+    let adjusted = (price as u128)
+        .checked_mul(multiplier as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(BPS_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    adjusted.try_into().map_err(|_| PerpError::MathOverflow.into())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
-pub enum OracleType {
-    Pyth, 
-    Custom,
-    None
+// usd = amount * price / 10^decimals, scaled from PRICE_PRECISION to USD_PRECISION
+fn token_amount_to_usd(amount: u64, decimals: u8, price: u64) -> Result<u64> {
+    let usd = (amount as u128)
+        .checked_mul(price as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_mul(USD_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(
+            10u128
+                .checked_pow(decimals as u32)
+                .ok_or(PerpError::MathOverflow)?
+                .checked_mul(PRICE_PRECISION as u128)
+                .ok_or(PerpError::MathOverflow)?
+        )
+        .ok_or(PerpError::MathOverflow)?;
+
+    usd.try_into().map_err(|_| PerpError::MathOverflow.into())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
-pub enum Side {
-    Long, 
-    Short
+// amount = usd * 10^decimals / price, scaled from USD_PRECISION to PRICE_PRECISION
+fn usd_to_token_amount(usd: u64, decimals: u8, price: u64) -> Result<u64> {
+    require!(price > 0, PerpError::InvalidOraclePrice);
+
+    let amount = (usd as u128)
+        .checked_mul(
+            10u128
+                .checked_pow(decimals as u32)
+                .ok_or(PerpError::MathOverflow)?
+        )
+        .ok_or(PerpError::MathOverflow)?
+        .checked_mul(PRICE_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(
+            (price as u128)
+                .checked_mul(USD_PRECISION as u128)
+                .ok_or(PerpError::MathOverflow)?
+        )
+        .ok_or(PerpError::MathOverflow)?;
+
+    amount.try_into().map_err(|_| PerpError::MathOverflow.into())
 }
 
-// Helper Functions
-fn calculate_pool_value(_pool: &Pool, custodies: &[Account<Custody>]) -> Result<u64> {
-    let mut total_value = 0u64;
-    
-    for custody in custodies {
-        let custody_value = custody.assets.owned
-            .checked_mul(custody.pricing.current_price)
-            .ok_or(PerpError::MathOverflow)?
-            .checked_div(PRICE_PRECISION)
-            .ok_or(PerpError::MathOverflow)?;
-        
-        total_value = total_value
-            .checked_add(custody_value)
-            .ok_or(PerpError::MathOverflow)?;
-    }
-    
-    Ok(total_value.max(1)) // Prevent division by zero
+// Keeps the sign of a USD amount (PnL, funding) while converting it to tokens
+fn signed_usd_to_token_amount(usd: i64, decimals: u8, price: u64) -> Result<i64> {
+    let amount: i64 = usd_to_token_amount(usd.unsigned_abs(), decimals, price)?
+        .try_into()
+        .map_err(|_| PerpError::MathOverflow)?;
+
+    Ok(if usd >= 0 { amount } else { -amount })
 }
 
 fn calculate_pnl(position: &Position, current_price: u64) -> Result<i64> {
@@ -1224,102 +4284,379 @@ fn calculate_short_pnl(position: &Position, current_price: u64) -> Result<i64> {
     pnl.try_into().map_err(|_| PerpError::MathOverflow.into())
 }
 
-fn get_oracle_price(custody: &Account<Custody>, oracle_account: &AccountInfo, clock: &Clock) -> Result<u64> {
+// Kinked model: base + slope1 up to optimal utilization, then slope2 on top
+fn get_borrow_rate(custody: &Custody) -> Result<u64> {
+    let params = &custody.borrow_rate;
+    if custody.assets.owned == 0 {
+        return Ok(params.base_rate);
+    }
+
+    let utilization = ((custody.assets.locked as u128)
+        .checked_mul(RATE_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(custody.assets.owned as u128)
+        .ok_or(PerpError::MathOverflow)? as u64)
+        .min(RATE_PRECISION);
+
+    let rate = if utilization <= params.optimal_utilization {
+        let variable = (params.slope1 as u128)
+            .checked_mul(utilization as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(params.optimal_utilization.max(1) as u128)
+            .ok_or(PerpError::MathOverflow)?;
+        (params.base_rate as u128)
+            .checked_add(variable)
+            .ok_or(PerpError::MathOverflow)?
+    } else {
+        let excess = (params.slope2 as u128)
+            .checked_mul((utilization - params.optimal_utilization) as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div((RATE_PRECISION - params.optimal_utilization).max(1) as u128)
+            .ok_or(PerpError::MathOverflow)?;
+        (params.base_rate as u128)
+            .checked_add(params.slope1 as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_add(excess)
+            .ok_or(PerpError::MathOverflow)?
+    };
+
+    rate.try_into().map_err(|_| PerpError::MathOverflow.into())
+}
+
+// Must run before anything that changes utilization so the elapsed period accrues at the old rate
+fn update_borrow_rate(custody: &mut Custody, current_time: i64) -> Result<()> {
+    let rate = get_borrow_rate(custody)?;
+    let state = &mut custody.borrow_rate_state;
+
+    let time_diff = current_time.saturating_sub(state.last_update);
+    if time_diff > 0 {
+        let interest = (rate as u128)
+            .checked_mul(time_diff as u128)
+            .ok_or(PerpError::MathOverflow)?;
+        state.cumulative_interest = state.cumulative_interest
+            .checked_add(interest)
+            .ok_or(PerpError::MathOverflow)?;
+        state.last_update = current_time;
+    }
+    state.current_rate = rate;
+
+    Ok(())
+}
+
+fn calculate_borrow_fee(position: &Position, custody: &Custody) -> Result<u64> {
+    let interest = custody.borrow_rate_state.cumulative_interest
+        .saturating_sub(position.cumulative_interest_snapshot);
+
+    let fee = (position.size_usd as u128)
+        .checked_mul(interest)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(RATE_PRECISION as u128 * SECONDS_PER_YEAR as u128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    fee.try_into().map_err(|_| PerpError::MathOverflow.into())
+}
+
+// Funding rate scales with OI skew: max_funding_rate * (long - short) / (long + short)
+fn get_funding_rate(custody: &Custody) -> Result<i64> {
+    let oi_long = custody.trade_stats.oi_long_usd as i128;
+    let oi_short = custody.trade_stats.oi_short_usd as i128;
+    let total_oi = oi_long + oi_short;
+    if total_oi == 0 {
+        return Ok(0);
+    }
+
+    let rate = (custody.pricing.max_funding_rate as i128)
+        .checked_mul(oi_long - oi_short)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(total_oi)
+        .ok_or(PerpError::MathOverflow)?;
+
+    rate.try_into().map_err(|_| PerpError::MathOverflow.into())
+}
+
+// The paying side accrues rate * seconds, the receiving side gets the same total spread over its own OI
+fn update_funding_rate(custody: &mut Custody, current_time: i64) -> Result<()> {
+    let rate = get_funding_rate(custody)?;
+    let oi_long = custody.trade_stats.oi_long_usd as i128;
+    let oi_short = custody.trade_stats.oi_short_usd as i128;
+    let state = &mut custody.funding_rate_state;
+
+    let time_diff = current_time.saturating_sub(state.last_update);
+    if time_diff > 0 {
+        let paid = (rate.unsigned_abs() as i128)
+            .checked_mul(time_diff as i128)
+            .ok_or(PerpError::MathOverflow)?;
+
+        let (long_delta, short_delta) = if rate > 0 {
+            let received = if oi_short > 0 { paid * oi_long / oi_short } else { 0 };
+            (paid, -received)
+        } else if rate < 0 {
+            let received = if oi_long > 0 { paid * oi_short / oi_long } else { 0 };
+            (-received, paid)
+        } else {
+            (0, 0)
+        };
+
+        state.cumulative_long = state.cumulative_long
+            .checked_add(long_delta)
+            .ok_or(PerpError::MathOverflow)?;
+        state.cumulative_short = state.cumulative_short
+            .checked_add(short_delta)
+            .ok_or(PerpError::MathOverflow)?;
+        state.last_update = current_time;
+
+        let long_funding = funding_amount(oi_long, long_delta)?;
+        let short_funding = funding_amount(oi_short, short_delta)?;
+        custody.trade_stats.total_long_funding = custody.trade_stats.total_long_funding
+            .checked_add(long_funding)
+            .ok_or(PerpError::MathOverflow)?;
+        custody.trade_stats.total_short_funding = custody.trade_stats.total_short_funding
+            .checked_add(short_funding)
+            .ok_or(PerpError::MathOverflow)?;
+    }
+    custody.funding_rate_state.current_rate = rate;
+
+    Ok(())
+}
+
+fn funding_amount(size: i128, funding_delta: i128) -> Result<i64> {
+    size
+        .checked_mul(funding_delta)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(RATE_PRECISION as i128 * SECONDS_PER_YEAR as i128)
+        .ok_or(PerpError::MathOverflow)?
+        .try_into()
+        .map_err(|_| PerpError::MathOverflow.into())
+}
+
+fn get_cumulative_funding(custody: &Custody, side: &Side) -> i128 {
+    match side {
+        Side::Long => custody.funding_rate_state.cumulative_long,
+        Side::Short => custody.funding_rate_state.cumulative_short,
+    }
+}
+
+// Positive when the position owes funding, negative when it is owed
+fn calculate_funding_payment(position: &Position, custody: &Custody) -> Result<i64> {
+    let funding_delta = get_cumulative_funding(custody, &position.side)
+        .checked_sub(position.funding_snapshot)
+        .ok_or(PerpError::MathOverflow)?;
+
+    funding_amount(position.size_usd as i128, funding_delta)
+}
+
+// Positive amounts were paid into the pool by a trader, negative amounts were paid out of it
+fn settle_pool_owned(custody: &mut Custody, paid_to_pool: i64) -> Result<()> {
+    custody.assets.owned = if paid_to_pool >= 0 {
+        custody.assets.owned.checked_add(paid_to_pool as u64)
+    } else {
+        custody.assets.owned.checked_sub(paid_to_pool.unsigned_abs())
+    }
+    .ok_or(PerpError::InsufficientLiquidity)?;
+
+    Ok(())
+}
+
+fn get_oracle_price(custody: &Custody, oracle_account: &AccountInfo, clock: &Clock) -> Result<OraclePrice> {
+    require!(!custody.trading_halted, PerpError::TradingHalted);
+    validate_oracle_account(custody, oracle_account)?;
+
     match custody.oracle_type {
         OracleType::Pyth => get_pyth_price(custody, oracle_account, clock),
-        OracleType::Custom => get_custom_price(oracle_account, clock),
-        OracleType::None => Ok(custody.pricing.current_price) // Return stored price for custom/stable assets
+        OracleType::Custom => get_custom_price(custody, oracle_account, clock),
+        OracleType::None => Ok(OraclePrice { // Return stored price for custom/stable assets
+            price: custody.pricing.current_price,
+            ema_price: custody.pricing.ema_price,
+        })
+    }
+}
+
+// Time weighted EMA, fully converging to the new price after an hour
+fn calculate_ema(ema_price: u64, new_price: u64, time_diff: i64) -> Result<u64> {
+    let alpha = (time_diff.clamp(0, 3600) as u128)
+        .checked_mul(1000)
+        .ok_or(PerpError::MathOverflow)?
+        / 3600;
+
+    let ema = (ema_price as u128)
+        .checked_mul(1000 - alpha)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_add(
+            (new_price as u128)
+                .checked_mul(alpha)
+                .ok_or(PerpError::MathOverflow)?
+        )
+        .ok_or(PerpError::MathOverflow)?
+        / 1000;
+
+    u64::try_from(ema).map_err(|_| PerpError::MathOverflow.into())
+}
+
+// A price outside the deviation band halts trading instead of being applied
+fn trip_circuit_breaker(custody: &mut Account<Custody>, previous_price: u64, new_price: u64) -> Result<bool> {
+    if price_deviation_bps(previous_price, new_price)? <= custody.pricing.max_price_deviation_bps {
+        return Ok(false);
+    }
+
+    custody.trading_halted = true;
+
+    emit!(CircuitBreakerTripped {
+        custody: custody.key(),
+        current_price: previous_price,
+        rejected_price: new_price,
+    });
+
+    Ok(true)
+}
+
+fn price_deviation_bps(previous_price: u64, new_price: u64) -> Result<u64> {
+    if previous_price == 0 {
+        return Ok(0);
     }
+
+    let deviation = (previous_price.abs_diff(new_price) as u128)
+        .checked_mul(BPS_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?
+        / previous_price as u128;
+
+    Ok(u64::try_from(deviation).unwrap_or(u64::MAX))
+}
+
+// The oracle must be the one bound to the custody and owned by the expected program
+fn validate_oracle_account(custody: &Custody, oracle_account: &AccountInfo) -> Result<()> {
+    let expected_owner = match custody.oracle_type {
+        OracleType::Pyth => pyth_solana_receiver_sdk::ID,
+        OracleType::Custom => crate::ID,
+        OracleType::None => return Ok(()), // stored price, no account is read
+    };
+
+    require_keys_eq!(oracle_account.key(), custody.oracle, PerpError::InvalidOracleAccount);
+    require_keys_eq!(*oracle_account.owner, expected_owner, PerpError::InvalidOracleAccount);
+
+    Ok(())
 }
 
-fn get_pyth_price(custody: &Account<Custody>, oracle_account: &AccountInfo, clock: &Clock) -> Result<u64> {
+fn get_pyth_price(custody: &Custody, oracle_account: &AccountInfo, clock: &Clock) -> Result<OraclePrice> {
     let price_update = PriceUpdateV2::try_deserialize(&mut oracle_account.data.borrow().as_ref())
         .map_err(|_| PerpError::InvalidOraclePrice)?;
 
-    let binding = "0xe62df6c8b4c85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43".to_string();
-    // Use configurable feed ID or fallback to default SOL/USD
     let feed_id_str = custody.feed_id.as_ref()
-        .unwrap_or(&binding);
-    
+        .ok_or(PerpError::InvalidOracleConfig)?;
+
     let feed_id = get_feed_id_from_hex(feed_id_str)
         .map_err(|_| PerpError::InvalidOraclePrice)?;
 
     let price_feed = price_update.get_price_no_older_than(
         clock, 
-        MAX_PRICE_AGE, 
+        custody.pricing.max_price_age_sec, 
         &feed_id,
     ).map_err(|_| PerpError::PriceTooOld)?;
 
-    if price_feed.price < 0 {
+    let message = &price_update.price_message;
+    if price_feed.price <= 0 || message.ema_price <= 0 {
         return Err(PerpError::InvalidOraclePrice.into());
     }
 
-    let price_u64 = price_feed.price as u64;
-    let expo = price_feed.exponent;
+    check_price_confidence(custody, price_feed.price as u64, price_feed.conf)?;
+    check_price_confidence(custody, message.ema_price as u64, message.ema_conf)?;
+
+    // Spot and EMA share the feed exponent
+    Ok(OraclePrice {
+        price: scale_oracle_price(price_feed.price as u64, price_feed.exponent)?,
+        ema_price: scale_oracle_price(message.ema_price as u64, price_feed.exponent)?,
+    })
+}
+
+// Reject prices whose confidence interval is wider than max_conf_bps of the price
+fn check_price_confidence(custody: &Custody, price: u64, conf: u64) -> Result<()> {
+    let max_conf = (price as u128)
+        .checked_mul(custody.pricing.max_conf_bps as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(BPS_PRECISION as u128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    require!((conf as u128) <= max_conf, PerpError::PriceConfidenceTooWide);
+
+    Ok(())
+}
 
-    // Convert to our PRICE_PRECISION (6 decimals)
+// Convert to our PRICE_PRECISION (6 decimals)
+fn scale_oracle_price(price: u64, expo: i32) -> Result<u64> {
     let normalized_price = if expo >= 0 {
         // Price has positive exponent, multiply
-        price_u64
-            .checked_mul(10_u64.pow(expo as u32))
+        price
+            .checked_mul(10_u64.checked_pow(expo as u32).ok_or(PerpError::MathOverflow)?)
             .ok_or(PerpError::MathOverflow)?
             .checked_mul(PRICE_PRECISION)
             .ok_or(PerpError::MathOverflow)?
     } else {
         // Price has negative exponent, need to adjust
-        let divisor = 10_u64.pow((-expo) as u32);
-        if divisor >= PRICE_PRECISION {
-            // Pyth has more decimals than our precision
-            price_u64
-                .checked_mul(PRICE_PRECISION)
-                .ok_or(PerpError::MathOverflow)?
-                .checked_div(divisor)
-                .ok_or(PerpError::MathOverflow)?
-        } else {
-            // Pyth has fewer decimals than our precision
-            price_u64
-                .checked_mul(PRICE_PRECISION)
-                .ok_or(PerpError::MathOverflow)?
-                .checked_div(divisor)
-                .ok_or(PerpError::MathOverflow)?
-        }
+        let divisor = 10_u128.checked_pow(expo.unsigned_abs()).ok_or(PerpError::MathOverflow)?;
+        let scaled = (price as u128)
+            .checked_mul(PRICE_PRECISION as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(divisor)
+            .ok_or(PerpError::MathOverflow)?;
+        u64::try_from(scaled).map_err(|_| PerpError::MathOverflow)?
     };
 
     Ok(normalized_price)
 }
 
-fn get_custom_price(oracle_account: &AccountInfo, _clock: &Clock) -> Result<u64> {
-    // This would be implemented based on custom oracle format
-    let data = oracle_account.try_borrow_data()?;
-    if data.len() < 8 {
-        return Err(PerpError::InvalidOraclePrice.into());
-    }
-
-    let price_bytes: [u8; 8] = data[0..8].try_into()
+fn get_custom_price(custody: &Custody, oracle_account: &AccountInfo, clock: &Clock) -> Result<OraclePrice> {
+    let oracle = CustomOracle::try_deserialize(&mut oracle_account.data.borrow().as_ref())
         .map_err(|_| PerpError::InvalidOraclePrice)?;
-    let price = u64::from_le_bytes(price_bytes);
 
-    Ok(price)
+    let max_age = i64::try_from(custody.pricing.max_price_age_sec)
+        .map_err(|_| PerpError::MathOverflow)?;
+    require!(
+        oracle.publish_time.saturating_add(max_age) >= clock.unix_timestamp,
+        PerpError::PriceTooOld
+    );
+
+    check_price_confidence(custody, oracle.price, oracle.conf)?;
+
+    Ok(OraclePrice {
+        price: scale_oracle_price(oracle.price, oracle.expo)?,
+        ema_price: scale_oracle_price(oracle.ema, oracle.expo)?,
+    })
 }
 
-pub fn calculate_liquidation_price(position: &Position, liquidation_threshold: u64) -> Result<u64> {
-    let threshold_ratio = liquidation_threshold as f64 / BPS_PRECISION as f64;
-    let leverage_ratio = position.leverage as f64;
+// Price at which losses plus closing costs use up liquidation_threshold of the collateral.
+// Costs are the close and liquidation fees plus borrow and funding accrued since the snapshots
+pub fn calculate_liquidation_price(position: &Position, custody: &Custody, liquidation_threshold: u64) -> Result<u64> {
+    require!(position.size_usd > 0, PerpError::InvalidAmount);
+
+    let close_fee = calculate_fee(position.size_usd, custody.fees.close_position)?;
+    let liquidation_fee = calculate_fee(position.size_usd, custody.fees.liquidation)?;
+    let borrow_fee = calculate_borrow_fee(position, custody)?;
+    let funding_payment = calculate_funding_payment(position, custody)?;
+
+    // USD loss the position can still absorb, negative if costs already exceed the margin
+    let max_loss = (calculate_fee(position.collateral_usd, liquidation_threshold)? as i128)
+        .checked_sub(close_fee as i128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_sub(liquidation_fee as i128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_sub(borrow_fee as i128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_sub(funding_payment as i128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    // loss = size * |price - entry| / entry
+    let price_distance = max_loss
+        .checked_mul(position.entry_price as i128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(position.size_usd as i128)
+        .ok_or(PerpError::MathOverflow)?;
 
     let liquidation_price = match position.side {
-        Side::Long => {
-            let price_drop_ratio = threshold_ratio / leverage_ratio;
-            let liquidation_price = position.entry_price as f64 * (1.0 - price_drop_ratio);
-            liquidation_price as u64
-        },
-        Side::Short => {
-            let price_rise_ratio = threshold_ratio / leverage_ratio;
-            let liquidation_price = position.entry_price as f64 * (1.0 + price_rise_ratio);
-            liquidation_price as u64
-        }
-    };
+        Side::Long => (position.entry_price as i128).checked_sub(price_distance),
+        Side::Short => (position.entry_price as i128).checked_add(price_distance),
+    }
+    .ok_or(PerpError::MathOverflow)?;
 
-    Ok(liquidation_price)
+    u64::try_from(liquidation_price.max(0)).map_err(|_| PerpError::MathOverflow.into())
 }
 
 #[error_code]
@@ -1350,4 +4687,58 @@ pub enum PerpError {
     SlippageExceeded,
     #[msg("Insufficient liquidity")]
     InsufficientLiquidity,
+    #[msg("Invalid swap custody")]
+    InvalidSwapCustody,
+    #[msg("Invalid pool accounts")]
+    InvalidPoolAccounts,
+    #[msg("Leverage exceeds custody max leverage")]
+    MaxLeverageExceeded,
+    #[msg("Max global long size exceeded")]
+    MaxGlobalLongSizeExceeded,
+    #[msg("Max global short size exceeded")]
+    MaxGlobalShortSizeExceeded,
+    #[msg("Invalid oracle config")]
+    InvalidOracleConfig,
+    #[msg("Invalid pricing config")]
+    InvalidPricingConfig,
+    #[msg("Invalid fee config")]
+    InvalidFeeConfig,
+    #[msg("Invalid borrow rate config")]
+    InvalidBorrowRateConfig,
+    #[msg("Invalid multisig config")]
+    InvalidMultisigConfig,
+    #[msg("Account is not authorized to sign this instruction")]
+    MultisigAccountNotAuthorized,
+    #[msg("Account has already signed this instruction")]
+    MultisigAlreadySigned,
+    #[msg("Pool already exists")]
+    PoolAlreadyExists,
+    #[msg("Custody already exists")]
+    CustodyAlreadyExists,
+    #[msg("Invalid oracle account")]
+    InvalidOracleAccount,
+    #[msg("Oracle price confidence too wide")]
+    PriceConfidenceTooWide,
+    #[msg("Trading halted by circuit breaker")]
+    TradingHalted,
+    #[msg("Trading is not halted")]
+    TradingNotHalted,
+    #[msg("Insufficient protocol fees")]
+    InsufficientFees,
+    #[msg("Invalid fee receiver")]
+    InvalidFeeReceiver,
+    #[msg("Too many open positions")]
+    MaxPositionsExceeded,
+    #[msg("Insufficient collateral")]
+    InsufficientCollateral,
+    #[msg("Insufficient unrealized profit")]
+    InsufficientPnl,
+    #[msg("Order trigger price not reached")]
+    OrderNotTriggered,
+    #[msg("Position already exists")]
+    PositionAlreadyExists,
+    #[msg("Position not found")]
+    PositionNotFound,
+    #[msg("Order was placed against a different position")]
+    OrderPositionMismatch,
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 import * as anchor from '@coral-xyz/anchor'
 import { Program } from '@coral-xyz/anchor'
 import { Keypair, LAMPORTS_PER_SOL, PublicKey, SystemProgram } from '@solana/web3.js'
-import { TOKEN_PROGRAM_ID, createMint, createAccount, mintTo, getAccount, getOrCreateAssociatedTokenAccount } from '@solana/spl-token'
+import { TOKEN_PROGRAM_ID, createMint, createAccount, mintTo, getAccount, getMint, getOrCreateAssociatedTokenAccount } from '@solana/spl-token'
 import { Perpetuals } from '../target/types/perpetuals'
 
 describe('Perpetuals', () => {
@@ -16,6 +16,7 @@ describe('Perpetuals', () => {
   const user = Keypair.generate()
 
   let perpetualsPda: PublicKey
+  let multisigPda: PublicKey
   let poolPda: PublicKey
   let lpTokenMint: PublicKey
   let custodyPda: PublicKey
@@ -26,8 +27,363 @@ describe('Perpetuals', () => {
   let mint: PublicKey
   let userTokenAccount: PublicKey
   let userLpTokenAccount: PublicKey
+  let stableMint: PublicKey
+  let stableCustodyPda: PublicKey
+  let stableCustodyTokenAccount: PublicKey
+  let userStableTokenAccount: PublicKey
+  let keeperTokenAccount: PublicKey
+  let oraclePoolPda: PublicKey
+  let oracleLpTokenMint: PublicKey
+  let oracleCustodyPda: PublicKey
+  let oracleCustodyTokenAccount: PublicKey
+  let customOraclePda: PublicKey
+
+  const executionFee = 1_000_000 // 0.001 SOL to the keeper
 
   const poolName = "test-pool"
+  const oraclePoolName = "oracle-pool" // single custody priced by a Custom oracle
+  const solUsdFeedId = "0xef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d"
+  // Loaded from tests/fixtures by Anchor.toml: $150 +/- $5, owned by the Pyth receiver
+  const wideConfPythPrice = new PublicKey("8YQXHvvBTE75mwcJ1AaGyGrfzqWe32NxJ3bhizCyX2To")
+
+  // Pool custodies followed by their oracle accounts (None oracles read the stored price)
+  const poolAccounts = (custodies: PublicKey[]) => [
+    ...custodies.map((pubkey) => ({ pubkey, isSigner: false, isWritable: false })),
+    ...custodies.map(() => ({ pubkey: user.publicKey, isSigner: false, isWritable: false })),
+  ]
+
+  // Position seeds: owner, pool, custody, side (0 long, 1 short), index as u64 LE
+  const findPositionPda = (owner: PublicKey, side: number, index: number) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        owner.toBuffer(),
+        poolPda.toBuffer(),
+        custodyPda.toBuffer(),
+        Buffer.from([side]),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 8)
+      ],
+      program.programId
+    )[0]
+
+  const findRegistryPda = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("position_registry"), owner.toBuffer()],
+      program.programId
+    )[0]
+
+  // Order seeds: owner, pool, custody, index as u64 LE; the escrow hangs off the order
+  const findOrderPda = (owner: PublicKey, index: number) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("order"),
+        owner.toBuffer(),
+        poolPda.toBuffer(),
+        custodyPda.toBuffer(),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 8)
+      ],
+      program.programId
+    )[0]
+
+  const findEscrowPda = (order: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("order_escrow"), order.toBuffer()],
+      program.programId
+    )[0]
+
+  const placeOrder = (
+    index: number,
+    orderType: any,
+    positionIndex: number,
+    collateralAmount: number,
+    leverage: number,
+    triggerPrice: number
+  ) => {
+    const orderPda = findOrderPda(user.publicKey, index)
+    return program.methods
+      .placeOrder(
+        new anchor.BN(index),
+        orderType,
+        { long: {} },
+        new anchor.BN(positionIndex),
+        new anchor.BN(collateralAmount),
+        new anchor.BN(leverage),
+        new anchor.BN(triggerPrice),
+        new anchor.BN(executionFee)
+      )
+      .accountsStrict({
+        owner: user.publicKey,
+        order: orderPda,
+        escrowAccount: findEscrowPda(orderPda),
+        position: findPositionPda(user.publicKey, 0, positionIndex),
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        fundingAccount: userTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId
+      })
+      .signers([user])
+      .rpc()
+  }
+
+  const executeOrder = (index: number, positionIndex: number) => {
+    const orderPda = findOrderPda(user.publicKey, index)
+    return program.methods
+      .executeOrder()
+      .accountsStrict({
+        keeper: authority.publicKey,
+        owner: user.publicKey,
+        order: orderPda,
+        escrowAccount: findEscrowPda(orderPda),
+        position: findPositionPda(user.publicKey, 0, positionIndex),
+        positionRegistry: findRegistryPda(user.publicKey),
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        ownerTokenAccount: userTokenAccount,
+        keeperTokenAccount: keeperTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId
+      })
+      .signers([authority])
+      .rpc()
+  }
+
+  const cancelOrder = (index: number) => {
+    const orderPda = findOrderPda(user.publicKey, index)
+    return program.methods
+      .cancelOrder()
+      .accountsStrict({
+        owner: user.publicKey,
+        order: orderPda,
+        escrowAccount: findEscrowPda(orderPda),
+        receivingAccount: userTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+  }
+
+  // Opens a 1 SOL position for the authority, used by the open position error cases
+  const openAuthorityPosition = async (side: any, leverage: number, acceptablePrice: number) => {
+    const authorityTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      authority,
+      mint,
+      authority.publicKey
+    )
+
+    return program.methods
+      .openPosition(
+        side,
+        new anchor.BN(0),
+        new anchor.BN(1 * LAMPORTS_PER_SOL),
+        new anchor.BN(leverage),
+        new anchor.BN(acceptablePrice)
+      )
+      .accountsStrict({
+        owner: authority.publicKey,
+        position: findPositionPda(authority.publicKey, side.long ? 0 : 1, 0),
+        positionRegistry: findRegistryPda(authority.publicKey),
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        collateralAccount: authorityTokenAccountInfo.address,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId
+      })
+      .signers([authority])
+      .rpc()
+  }
+
+  const setCustodyPricing = async (pricing: any) => {
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+
+    return program.methods
+      .setCustodyConfig(
+        custodyAcc.isStable,
+        custodyAcc.oracle,
+        custodyAcc.oracleType,
+        custodyAcc.feedId,
+        pricing,
+        custodyAcc.fees,
+        custodyAcc.borrowRate
+      )
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: custodyPda,
+        pool: poolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+  }
+
+  const setCustodyBorrowRate = async (borrowRate: any) => {
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+
+    return program.methods
+      .setCustodyConfig(
+        custodyAcc.isStable,
+        custodyAcc.oracle,
+        custodyAcc.oracleType,
+        custodyAcc.feedId,
+        custodyAcc.pricing,
+        custodyAcc.fees,
+        borrowRate
+      )
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: custodyPda,
+        pool: poolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+  }
+
+  // Opens or closes a 10x long for the user at the given index
+  const openUserPosition = (index: number, collateralAmount: anchor.BN) =>
+    program.methods
+      .openPosition({ long: {} }, new anchor.BN(index), collateralAmount, new anchor.BN(100_000), new anchor.BN(100 * 1_000_000))
+      .accountsStrict({
+        owner: user.publicKey,
+        position: findPositionPda(user.publicKey, 0, index),
+        positionRegistry: findRegistryPda(user.publicKey),
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        collateralAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId
+      })
+      .signers([user])
+      .rpc()
+
+  const closeUserPosition = (index: number, side = 0) =>
+    program.methods
+      .closePosition()
+      .accountsStrict({
+        owner: user.publicKey,
+        position: findPositionPda(user.publicKey, side, index),
+        positionRegistry: findRegistryPda(user.publicKey),
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        receivingAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+
+  const setPrice = (price: anchor.BN) =>
+    program.methods
+      .updatePrice(price)
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: custodyPda,
+        pool: poolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+  const setCustomOraclePrice = (price: number, expo: number, conf: number, ema: number, publishTime: number) =>
+    program.methods
+      .setCustomOraclePrice(
+        new anchor.BN(price),
+        expo,
+        new anchor.BN(conf),
+        new anchor.BN(ema),
+        new anchor.BN(publishTime)
+      )
+      .accountsStrict({
+        authority: authority.publicKey,
+        oracleAccount: customOraclePda,
+        custody: oracleCustodyPda,
+        pool: oraclePoolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        systemProgram: SystemProgram.programId
+      })
+      .signers([authority])
+      .rpc()
+
+  const addOracleLiquidity = async (amountIn: anchor.BN, oracleAccount: PublicKey) => {
+    const lpTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      user,
+      oracleLpTokenMint,
+      user.publicKey
+    )
+
+    return program.methods
+      .addLiquidity(amountIn, new anchor.BN(0))
+      .accountsStrict({
+        owner: user.publicKey,
+        perpetuals: perpetualsPda,
+        pool: oraclePoolPda,
+        custody: oracleCustodyPda,
+        custodyTokenMint: mint,
+        lpTokenMint: oracleLpTokenMint,
+        fundingAccount: userTokenAccount,
+        lpTokenAccount: lpTokenAccountInfo.address,
+        custodyTokenAccount: oracleCustodyTokenAccount,
+        oracleAccount: oracleAccount,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .remainingAccounts([
+        { pubkey: oracleCustodyPda, isSigner: false, isWritable: false },
+        { pubkey: oracleAccount, isSigner: false, isWritable: false },
+      ])
+      .signers([user])
+      .rpc()
+  }
+
+  const setOracleCustodyOracle = async (oracle: PublicKey, oracleType: any, feedId: string | null) => {
+    const custodyAcc = await program.account.custody.fetch(oracleCustodyPda)
+
+    return program.methods
+      .setCustodyConfig(
+        custodyAcc.isStable,
+        oracle,
+        oracleType,
+        feedId,
+        custodyAcc.pricing,
+        custodyAcc.fees,
+        custodyAcc.borrowRate
+      )
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: oracleCustodyPda,
+        pool: oraclePoolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+  }
 
   beforeAll(async () => {
     // Airdrop SOL to authority and user
@@ -71,6 +427,11 @@ describe('Perpetuals', () => {
       program.programId
     )
 
+    ;[multisigPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("multisig")],
+      program.programId
+    )
+
     ;[poolPda] = PublicKey.findProgramAddressSync(
       [Buffer.from("pool"), Buffer.from(poolName)],
       program.programId
@@ -91,12 +452,34 @@ describe('Perpetuals', () => {
       program.programId
     )
 
-    ;[positionPda] = PublicKey.findProgramAddressSync(
-      [Buffer.from("position"), user.publicKey.toBuffer(), poolPda.toBuffer(), custodyPda.toBuffer()],
+    ;[oraclePoolPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), Buffer.from(oraclePoolName)],
+      program.programId
+    )
+
+    ;[oracleLpTokenMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from("lp_token_mint"), oraclePoolPda.toBuffer()],
+      program.programId
+    )
+
+    ;[oracleCustodyPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("custody"), oraclePoolPda.toBuffer(), mint.toBuffer()],
+      program.programId
+    )
+
+    ;[oracleCustodyTokenAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("custody_token_account"), oraclePoolPda.toBuffer(), mint.toBuffer()],
       program.programId
     )
 
-    minSignatures = 0
+    ;[customOraclePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("oracle_account"), oraclePoolPda.toBuffer(), mint.toBuffer()],
+      program.programId
+    )
+
+    positionPda = findPositionPda(user.publicKey, 0, 0)
+
+    minSignatures = 1
     admins = [authority.publicKey]
   })
 
@@ -106,6 +489,7 @@ describe('Perpetuals', () => {
       .accountsStrict({
         admin: authority.publicKey,
         perpetuals: perpetualsPda,
+        multisig: multisigPda,
         systemProgram: SystemProgram.programId
       })
       .signers([authority])
@@ -125,6 +509,7 @@ describe('Perpetuals', () => {
         pool: poolPda,
         lpTokenMint: lpTokenMint,
         perpetuals: perpetualsPda,
+        multisig: multisigPda,
         tokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId
       })
@@ -146,13 +531,14 @@ describe('Perpetuals', () => {
     const initialPrice = 50 * 1_000_000 // $50 with 6 decimals precision
 
     const tx = await program.methods
-      .addCustody(isStable, oracleType, new anchor.BN(initialPrice))
+      .addCustody(isStable, PublicKey.default, oracleType, null, new anchor.BN(initialPrice))
       .accountsStrict({
         authority: authority.publicKey,
         custody: custodyPda,
         custodyTokenMint: mint,
         pool: poolPda,
         perpetuals: perpetualsPda,
+        multisig: multisigPda,
         custodyTokenAccount: custodyTokenAccount,
         systemProgram: SystemProgram.programId,
         tokenProgram: TOKEN_PROGRAM_ID
@@ -179,7 +565,8 @@ describe('Perpetuals', () => {
         custody: custodyPda,
         pool: poolPda,
         mint: mint,
-        perpetuals: perpetualsPda
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
       })
       .signers([authority])
       .rpc()
@@ -190,77 +577,516 @@ describe('Perpetuals', () => {
     console.log(custodyAcc);
   })
 
-  it('Add Liquidity', async () => {
-    // Create user LP token account using associated token account
-    const userLpTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-      provider.connection,
-      user,
-      lpTokenMint,
-      user.publicKey
-    )
-    userLpTokenAccount = userLpTokenAccountInfo.address
-
-    const amountIn = 100 * LAMPORTS_PER_SOL // 100 tokens
-    const minLpAmountOut = 0
-
-    const initialBalance = await getAccount(provider.connection, userTokenAccount)
-    console.log("User initial token balance:", initialBalance.amount.toString())
-
-    const tx = await program.methods
-      .addLiquidity(new anchor.BN(amountIn), new anchor.BN(minLpAmountOut))
+  it('Add Custom Oracle Custody', async () => {
+    await program.methods
+      .addPool(oraclePoolName)
       .accountsStrict({
-        owner: user.publicKey,
+        authority: authority.publicKey,
+        pool: oraclePoolPda,
+        lpTokenMint: oracleLpTokenMint,
         perpetuals: perpetualsPda,
-        pool: poolPda,
-        custody: custodyPda,
+        multisig: multisigPda,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId
+      })
+      .signers([authority])
+      .rpc()
+
+    await program.methods
+      .addCustody(false, customOraclePda, { custom: {} }, null, new anchor.BN(55 * 1_000_000))
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: oracleCustodyPda,
         custodyTokenMint: mint,
-        lpTokenMint: lpTokenMint,
-        fundingAccount: userTokenAccount,
-        lpTokenAccount: userLpTokenAccount,
-        custodyTokenAccount: custodyTokenAccount,
+        pool: oraclePoolPda,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda,
+        custodyTokenAccount: oracleCustodyTokenAccount,
+        systemProgram: SystemProgram.programId,
         tokenProgram: TOKEN_PROGRAM_ID
       })
-      .signers([user])
+      .signers([authority])
       .rpc()
 
-    console.log("Add liquidity tx:", tx)
+    const custodyAcc = await program.account.custody.fetch(oracleCustodyPda)
+    expect(custodyAcc.oracleType).toEqual({ custom: {} })
+    expect(custodyAcc.oracle.toBase58()).toBe(customOraclePda.toBase58())
+  })
 
-    const userTokenBalance = await getAccount(provider.connection, userTokenAccount)
-    const userLpBalance = await getAccount(provider.connection, userLpTokenAccount)
-    const custodyBalance = await getAccount(provider.connection, custodyTokenAccount)
+  it('Error: Stale custom oracle price', async () => {
+    // Older than the default 60s max_price_age_sec
+    await setCustomOraclePrice(5_500_000_000, -8, 1_000_000, 5_450_000_000, Math.floor(Date.now() / 1000) - 120)
 
-    console.log("User token balance after:", userTokenBalance.amount.toString())
-    console.log("User LP token balance:", userLpBalance.amount.toString())
-    console.log("Custody token balance:", custodyBalance.amount.toString())
+    try {
+      await addOracleLiquidity(new anchor.BN(1 * LAMPORTS_PER_SOL), customOraclePda)
+      throw new Error("Should have failed with a stale oracle price")
+    } catch (error: any) {
+      expect(error.error?.errorCode?.code).toBe("PriceTooOld")
+    }
   })
 
-  it('Open Long Position', async () => {
-    const side = { long: {} }
-    const collateralAmount = 1 * LAMPORTS_PER_SOL // 1 SOL collateral
-    const leverage = 10 // 10x leverage
-    const acceptablePrice = 60 * 1_000_000 // Accept up to $60
+  it('Set Custom Oracle Price', async () => {
+    const publishTime = Math.floor(Date.now() / 1000) - 5
 
-    // Mock oracle account (using user's account as placeholder since we're using None oracle type)
-    const oracleAccount = user.publicKey
+    const tx = await setCustomOraclePrice(
+      5_500_000_000, // $55 with expo -8
+      -8,
+      1_000_000,
+      5_450_000_000,
+      publishTime
+    )
 
-    const userBalanceBefore = await getAccount(provider.connection, userTokenAccount)
-    console.log("User token balance before position:", userBalanceBefore.amount.toString())
+    console.log("Set custom oracle price tx:", tx)
 
-    const tx = await program.methods
-      .openPosition(
-        side,
-        new anchor.BN(collateralAmount),
-        new anchor.BN(leverage),
-        new anchor.BN(acceptablePrice)
-      )
+    const oracleAcc = await program.account.customOracle.fetch(customOraclePda)
+    expect(oracleAcc.price.toNumber()).toBe(5_500_000_000)
+    expect(oracleAcc.expo).toBe(-8)
+    expect(oracleAcc.publishTime.toNumber()).toBe(publishTime)
+  })
+
+  it('Custom oracle price is normalized', async () => {
+    const amountIn = 1 * LAMPORTS_PER_SOL
+    await addOracleLiquidity(new anchor.BN(amountIn), customOraclePda)
+
+    // First deposit mints 1:1 with USD at the lower of spot ($55) and EMA ($54.50),
+    // both scaled from expo -8 to PRICE_PRECISION
+    const netAmount = amountIn - (amountIn * 30) / 10_000
+    const expectedLp = (netAmount * 54_500_000) / LAMPORTS_PER_SOL
+    const lpMint = await getMint(provider.connection, oracleLpTokenMint)
+    expect(Number(lpMint.supply)).toBe(expectedLp)
+  })
+
+  it('Custom oracle circuit breaker', async () => {
+    // A 100% jump is outside the deviation band and halts trading
+    await setCustomOraclePrice(11_000_000_000, -8, 1_000_000, 5_450_000_000, Math.floor(Date.now() / 1000) - 4)
+
+    let custodyAcc = await program.account.custody.fetch(oracleCustodyPda)
+    expect(custodyAcc.tradingHalted).toBe(true)
+    const oracleAcc = await program.account.customOracle.fetch(customOraclePda)
+    expect(oracleAcc.price.toNumber()).toBe(5_500_000_000)
+
+    await program.methods
+      .resetCircuitBreaker(new anchor.BN(55 * 1_000_000))
       .accountsStrict({
-        owner: user.publicKey,
-        position: positionPda,
-        perpetuals: perpetualsPda,
-        pool: poolPda,
-        custody: custodyPda,
+        authority: authority.publicKey,
+        custody: oracleCustodyPda,
+        pool: oraclePoolPda,
         mint: mint,
-        custodyTokenAccount: custodyTokenAccount,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+    custodyAcc = await program.account.custody.fetch(oracleCustodyPda)
+    expect(custodyAcc.tradingHalted).toBe(false)
+  })
+
+  it('Error: Wrong oracle account', async () => {
+    await setCustomOraclePrice(5_500_000_000, -8, 1_000_000, 5_450_000_000, Math.floor(Date.now() / 1000) - 1)
+
+    try {
+      // Program owned, but not the oracle bound to the custody
+      await addOracleLiquidity(new anchor.BN(1 * LAMPORTS_PER_SOL), oracleCustodyPda)
+      throw new Error("Should have failed with the wrong oracle account")
+    } catch (error: any) {
+      expect(error.error?.errorCode?.code).toBe("InvalidOracleAccount")
+    }
+  })
+
+  it('Error: Oracle account with the wrong owner', async () => {
+    // Bound key, but a Pyth custody expects an account owned by the Pyth receiver
+    await setOracleCustodyOracle(customOraclePda, { pyth: {} }, solUsdFeedId)
+
+    try {
+      await addOracleLiquidity(new anchor.BN(1 * LAMPORTS_PER_SOL), customOraclePda)
+      throw new Error("Should have failed with the wrong oracle owner")
+    } catch (error: any) {
+      expect(error.error?.errorCode?.code).toBe("InvalidOracleAccount")
+    }
+
+    await setOracleCustodyOracle(customOraclePda, { custom: {} }, null)
+  })
+
+  it('Error: Pyth price with a wide confidence interval', async () => {
+    await setOracleCustodyOracle(wideConfPythPrice, { pyth: {} }, solUsdFeedId)
+
+    try {
+      await addOracleLiquidity(new anchor.BN(1 * LAMPORTS_PER_SOL), wideConfPythPrice)
+      throw new Error("Should have failed with a wide confidence interval")
+    } catch (error: any) {
+      expect(error.error?.errorCode?.code).toBe("PriceConfidenceTooWide")
+    }
+
+    await setOracleCustodyOracle(customOraclePda, { custom: {} }, null)
+  })
+
+  it('Error: Custom oracle price on a non-Custom custody', async () => {
+    const [oraclePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("oracle_account"), poolPda.toBuffer(), mint.toBuffer()],
+      program.programId
+    )
+
+    try {
+      await program.methods
+        .setCustomOraclePrice(
+          new anchor.BN(5_500_000_000),
+          -8,
+          new anchor.BN(1_000_000),
+          new anchor.BN(5_450_000_000),
+          new anchor.BN(Math.floor(Date.now() / 1000) - 5)
+        )
+        .accountsStrict({
+          authority: authority.publicKey,
+          oracleAccount: oraclePda,
+          custody: custodyPda,
+          pool: poolPda,
+          mint: mint,
+          perpetuals: perpetualsPda,
+          systemProgram: SystemProgram.programId
+        })
+        .signers([authority])
+        .rpc()
+      throw new Error("Should have failed on a None oracle custody")
+    } catch (error: any) {
+      expect(error.error?.errorCode?.code).toBe("InvalidOracleConfig")
+    }
+  })
+
+  it('Set Custody Config', async () => {
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+
+    const fees = { ...custodyAcc.fees, openPosition: new anchor.BN(80) } // 0.80%
+    // Trade on spot: the EMA lags the $55 update by an hour, and the wider of spot and EMA
+    // would leave 10x longs past their liquidation price on open
+    const pricing = { ...custodyAcc.pricing, maxPriceAgeSec: new anchor.BN(10), useEma: false }
+
+    const tx = await program.methods
+      .setCustodyConfig(
+        custodyAcc.isStable,
+        custodyAcc.oracle,
+        custodyAcc.oracleType,
+        custodyAcc.feedId,
+        pricing,
+        fees,
+        custodyAcc.borrowRate
+      )
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: custodyPda,
+        pool: poolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+    console.log("Set custody config tx:", tx)
+
+    const updatedCustody = await program.account.custody.fetch(custodyPda)
+    expect(updatedCustody.fees.openPosition.toNumber()).toBe(80)
+    expect(updatedCustody.pricing.maxPriceAgeSec.toNumber()).toBe(10)
+    expect(updatedCustody.pricing.useEma).toBe(false)
+  })
+
+  it('Error: Invalid custody fee config', async () => {
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+
+    const fees = { ...custodyAcc.fees, closePosition: new anchor.BN(10_001) } // above 100%
+
+    try {
+      await program.methods
+        .setCustodyConfig(
+          custodyAcc.isStable,
+          custodyAcc.oracle,
+          custodyAcc.oracleType,
+          custodyAcc.feedId,
+          custodyAcc.pricing,
+          fees,
+          custodyAcc.borrowRate
+        )
+        .accountsStrict({
+          authority: authority.publicKey,
+          custody: custodyPda,
+          pool: poolPda,
+          mint: mint,
+          perpetuals: perpetualsPda,
+          multisig: multisigPda
+        })
+        .signers([authority])
+        .rpc()
+
+      throw new Error("Should have failed with invalid fee config")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toContain("InvalidFeeConfig")
+    }
+  })
+
+  it('Error: Invalid custody borrow rate config', async () => {
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+
+    const borrowRate = { ...custodyAcc.borrowRate, slope2: new anchor.BN(10_000_001) } // above 1000% a year
+
+    try {
+      await program.methods
+        .setCustodyConfig(
+          custodyAcc.isStable,
+          custodyAcc.oracle,
+          custodyAcc.oracleType,
+          custodyAcc.feedId,
+          custodyAcc.pricing,
+          custodyAcc.fees,
+          borrowRate
+        )
+        .accountsStrict({
+          authority: authority.publicKey,
+          custody: custodyPda,
+          pool: poolPda,
+          mint: mint,
+          perpetuals: perpetualsPda,
+          multisig: multisigPda
+        })
+        .signers([authority])
+        .rpc()
+
+      throw new Error("Should have failed with invalid borrow rate config")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toContain("InvalidBorrowRateConfig")
+    }
+  })
+
+  it('Add Liquidity', async () => {
+    // Create user LP token account using associated token account
+    const userLpTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      user,
+      lpTokenMint,
+      user.publicKey
+    )
+    userLpTokenAccount = userLpTokenAccountInfo.address
+
+    const amountIn = 100 * LAMPORTS_PER_SOL // 100 tokens
+    const minLpAmountOut = 0
+
+    const initialBalance = await getAccount(provider.connection, userTokenAccount)
+    console.log("User initial token balance:", initialBalance.amount.toString())
+
+    const tx = await program.methods
+      .addLiquidity(new anchor.BN(amountIn), new anchor.BN(minLpAmountOut))
+      .accountsStrict({
+        owner: user.publicKey,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        custodyTokenMint: mint,
+        lpTokenMint: lpTokenMint,
+        fundingAccount: userTokenAccount,
+        lpTokenAccount: userLpTokenAccount,
+        custodyTokenAccount: custodyTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .remainingAccounts(poolAccounts([custodyPda]))
+      .signers([user])
+      .rpc()
+
+    console.log("Add liquidity tx:", tx)
+
+    const poolAcc = await program.account.pool.fetch(poolPda)
+    console.log("Pool AUM (USD):", poolAcc.aumUsd.toString())
+
+    const userTokenBalance = await getAccount(provider.connection, userTokenAccount)
+    const userLpBalance = await getAccount(provider.connection, userLpTokenAccount)
+    const custodyBalance = await getAccount(provider.connection, custodyTokenAccount)
+
+    console.log("User token balance after:", userTokenBalance.amount.toString())
+    console.log("User LP token balance:", userLpBalance.amount.toString())
+    console.log("Custody token balance:", custodyBalance.amount.toString())
+  })
+
+  it('Swap', async () => {
+    // Create a stable custody to swap against
+    stableMint = await createMint(
+      provider.connection,
+      authority,
+      authority.publicKey,
+      null,
+      6 // 6 decimals for USDC-like token
+    )
+
+    ;[stableCustodyPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("custody"), poolPda.toBuffer(), stableMint.toBuffer()],
+      program.programId
+    )
+
+    ;[stableCustodyTokenAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("custody_token_account"), poolPda.toBuffer(), stableMint.toBuffer()],
+      program.programId
+    )
+
+    await program.methods
+      .addCustody(true, PublicKey.default, { none: {} }, null, new anchor.BN(1_000_000))
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: stableCustodyPda,
+        custodyTokenMint: stableMint,
+        pool: poolPda,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda,
+        custodyTokenAccount: stableCustodyTokenAccount,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([authority])
+      .rpc()
+
+    const userStableAccountInfo = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      user,
+      stableMint,
+      user.publicKey
+    )
+    userStableTokenAccount = userStableAccountInfo.address
+
+    await mintTo(
+      provider.connection,
+      authority,
+      stableMint,
+      userStableTokenAccount,
+      authority,
+      10_000 * 1_000_000 // 10,000 stable tokens
+    )
+
+    // LP minted = deposit value * supply / pool value, with the pool valued across both custodies
+    const stableDeposit = new anchor.BN(5_000 * 1_000_000)
+    const lpSupplyBefore = new anchor.BN((await getMint(provider.connection, lpTokenMint)).supply.toString())
+    const lpBalanceBefore = await getAccount(provider.connection, userLpTokenAccount)
+    const mainCustody = await program.account.custody.fetch(custodyPda)
+    const mainAumPrice = mainCustody.pricing.useEma ? mainCustody.pricing.emaPrice : mainCustody.pricing.currentPrice
+    const poolValue = mainCustody.assets.owned.mul(mainAumPrice).div(new anchor.BN(LAMPORTS_PER_SOL))
+    const stableFee = stableDeposit.muln(30).divn(10_000) // add_liquidity fee
+    const depositUsd = stableDeposit.sub(stableFee) // stable price is $1 and 6 decimals, so tokens == USD
+    const expectedLp = depositUsd.mul(lpSupplyBefore).div(poolValue)
+
+    // Seed the stable custody with liquidity so it can dispense
+    await program.methods
+      .addLiquidity(stableDeposit, new anchor.BN(0))
+      .accountsStrict({
+        owner: user.publicKey,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: stableCustodyPda,
+        custodyTokenMint: stableMint,
+        lpTokenMint: lpTokenMint,
+        fundingAccount: userStableTokenAccount,
+        lpTokenAccount: userLpTokenAccount,
+        custodyTokenAccount: stableCustodyTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .remainingAccounts(poolAccounts([custodyPda, stableCustodyPda]))
+      .signers([user])
+      .rpc()
+
+    const lpBalanceAfter = await getAccount(provider.connection, userLpTokenAccount)
+    expect((lpBalanceAfter.amount - lpBalanceBefore.amount).toString()).toBe(expectedLp.toString())
+
+    const stableBefore = await getAccount(provider.connection, userStableTokenAccount)
+
+    // Swap 1 token for stable tokens
+    const tx = await program.methods
+      .swap(new anchor.BN(1 * LAMPORTS_PER_SOL), new anchor.BN(1))
+      .accountsStrict({
+        owner: user.publicKey,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        receivingCustody: custodyPda,
+        receivingCustodyTokenMint: mint,
+        receivingCustodyTokenAccount: custodyTokenAccount,
+        receivingOracleAccount: user.publicKey,
+        dispensingCustody: stableCustodyPda,
+        dispensingCustodyTokenMint: stableMint,
+        dispensingCustodyTokenAccount: stableCustodyTokenAccount,
+        dispensingOracleAccount: user.publicKey,
+        fundingAccount: userTokenAccount,
+        receivingAccount: userStableTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+
+    console.log("Swap tx:", tx)
+
+    const stableAfter = await getAccount(provider.connection, userStableTokenAccount)
+    expect(Number(stableAfter.amount)).toBeGreaterThan(Number(stableBefore.amount))
+
+    const custodyAcc = await program.account.custody.fetch(stableCustodyPda)
+    console.log("Stable custody swap volume:", custodyAcc.volumeStats.swapUsd.toString())
+  })
+
+  it('Circuit Breaker', async () => {
+    const accounts = {
+      authority: authority.publicKey,
+      custody: stableCustodyPda,
+      pool: poolPda,
+      mint: stableMint,
+      perpetuals: perpetualsPda,
+      multisig: multisigPda
+    }
+
+    // A 100% jump is outside the deviation band and halts trading
+    await program.methods
+      .updatePrice(new anchor.BN(2_000_000))
+      .accountsStrict(accounts)
+      .signers([authority])
+      .rpc()
+
+    let custodyAcc = await program.account.custody.fetch(stableCustodyPda)
+    expect(custodyAcc.tradingHalted).toBe(true)
+    expect(custodyAcc.pricing.currentPrice.toNumber()).toBe(1_000_000)
+
+    await program.methods
+      .resetCircuitBreaker(new anchor.BN(1_000_000))
+      .accountsStrict(accounts)
+      .signers([authority])
+      .rpc()
+
+    custodyAcc = await program.account.custody.fetch(stableCustodyPda)
+    expect(custodyAcc.tradingHalted).toBe(false)
+  })
+
+  it('Open Long Position', async () => {
+    const side = { long: {} }
+    const collateralAmount = 1 * LAMPORTS_PER_SOL // 1 SOL collateral
+    const leverage = 100_000 // 10x leverage (BPS)
+    const acceptablePrice = 60 * 1_000_000 // Accept up to $60
+
+    // Mock oracle account (using user's account as placeholder since we're using None oracle type)
+    const oracleAccount = user.publicKey
+
+    const userBalanceBefore = await getAccount(provider.connection, userTokenAccount)
+    console.log("User token balance before position:", userBalanceBefore.amount.toString())
+
+    const tx = await program.methods
+      .openPosition(
+        side,
+        new anchor.BN(0),
+        new anchor.BN(collateralAmount),
+        new anchor.BN(leverage),
+        new anchor.BN(acceptablePrice)
+      )
+      .accountsStrict({
+        owner: user.publicKey,
+        position: positionPda,
+        positionRegistry: findRegistryPda(user.publicKey),
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
         collateralAccount: userTokenAccount, // Added missing collateral account
         oracleAccount: oracleAccount,
         tokenProgram: TOKEN_PROGRAM_ID,
@@ -274,6 +1100,23 @@ describe('Perpetuals', () => {
     const positionAcc = await program.account.position.fetch(positionPda)
     console.log("Position account:", positionAcc)
 
+    // Longs enter above the oracle by trade_spread_long
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+    expect(positionAcc.entryPrice.toNumber()).toBeGreaterThan(custodyAcc.pricing.currentPrice.toNumber())
+
+    // Size is in USD: 1 SOL of collateral at the oracle min price (spot, use_ema is off), times leverage
+    const expectedCollateralUsd = custodyAcc.pricing.currentPrice.toNumber() // 1 token at PRICE_PRECISION = USD_PRECISION
+    expect(expectedCollateralUsd).toBeLessThan(positionAcc.entryPrice.toNumber())
+    expect(positionAcc.collateralUsd.toNumber()).toBe(expectedCollateralUsd)
+    expect(positionAcc.sizeUsd.toNumber()).toBe(expectedCollateralUsd * leverage / 10_000)
+
+    // Long liquidation sits below entry, tightened by fees
+    expect(positionAcc.liquidationPrice.toNumber()).toBeGreaterThan(0)
+    expect(positionAcc.liquidationPrice.toNumber()).toBeLessThan(positionAcc.entryPrice.toNumber())
+
+    const registryAcc = await program.account.positionRegistry.fetch(findRegistryPda(user.publicKey))
+    expect(registryAcc.positions.map((p) => p.toBase58())).toContain(positionPda.toBase58())
+
     const userBalanceAfter = await getAccount(provider.connection, userTokenAccount)
     console.log("User token balance after position:", userBalanceAfter.amount.toString())
   })
@@ -289,6 +1132,13 @@ describe('Perpetuals', () => {
 
     const oracleAccount = user.publicKey
 
+    // Max out the borrow rate and let interest accrue so the update charges a visible borrow fee
+    const borrowRate = (await program.account.custody.fetch(custodyPda)).borrowRate
+    await setCustodyBorrowRate({ ...borrowRate, baseRate: new anchor.BN(10_000_000) })
+    await new Promise((resolve) => setTimeout(resolve, 2000))
+    const positionBefore = await program.account.position.fetch(positionPda)
+    const custodyBefore = await program.account.custody.fetch(custodyPda)
+
     const tx = await program.methods
       .updatePosition()
       .accountsStrict({
@@ -300,61 +1150,588 @@ describe('Perpetuals', () => {
       })
       .rpc()
 
-    console.log("Update position tx:", tx)
+    console.log("Update position tx:", tx)
+
+    const positionAcc = await program.account.position.fetch(positionPda)
+    console.log("Position after update:", positionAcc)
+
+    // Borrow fee comes out of collateral, the protocol takes its share and the snapshot moves up
+    const custodyAfter = await program.account.custody.fetch(custodyPda)
+    expect(positionAcc.collateralAmount.lt(positionBefore.collateralAmount)).toBe(true)
+    expect(custodyAfter.assets.protocolFees.gt(custodyBefore.assets.protocolFees)).toBe(true)
+    expect(positionAcc.cumulativeInterestSnapshot.gt(positionBefore.cumulativeInterestSnapshot)).toBe(true)
+    expect(positionAcc.cumulativeInterestSnapshot.toString())
+      .toBe(custodyAfter.borrowRateState.cumulativeInterest.toString())
+
+    await setCustodyBorrowRate(borrowRate)
+  })
+
+  it('Update Funding', async () => {
+    // The open long leaves the custody skewed long, so longs pay funding
+    const custodyBefore = await program.account.custody.fetch(custodyPda)
+    expect(custodyBefore.tradeStats.oiLongUsd.gt(custodyBefore.tradeStats.oiShortUsd)).toBe(true)
+
+    // Let the clock move so the update accrues
+    await new Promise((resolve) => setTimeout(resolve, 2000))
+
+    const tx = await program.methods
+      .updateFunding()
+      .accountsStrict({
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint
+      })
+      .rpc()
+
+    console.log("Update funding tx:", tx)
+
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+    console.log("Funding state:", custodyAcc.fundingRateState)
+    console.log("Total long funding:", custodyAcc.tradeStats.totalLongFunding.toString())
+
+    const fundingBefore = custodyBefore.fundingRateState
+    const fundingAfter = custodyAcc.fundingRateState
+    expect(fundingAfter.lastUpdate.gt(fundingBefore.lastUpdate)).toBe(true)
+    expect(fundingAfter.currentRate.toNumber()).toBeGreaterThan(0)
+    expect(fundingAfter.cumulativeLong.gt(fundingBefore.cumulativeLong)).toBe(true)
+  })
+
+  it('Increase Position', async () => {
+    let positionBefore
+    try {
+      positionBefore = await program.account.position.fetch(positionPda)
+    } catch (error) {
+      console.log("Skipping increase position test - position doesn't exist")
+      return
+    }
+
+    const sizeDelta = positionBefore.sizeUsd.divn(2)
+
+    const tx = await program.methods
+      .increasePosition(
+        new anchor.BN(0.75 * LAMPORTS_PER_SOL), // keeps leverage under the 10x custody max
+        sizeDelta,
+        new anchor.BN(60 * 1_000_000) // accept up to $60
+      )
+      .accountsStrict({
+        owner: user.publicKey,
+        position: positionPda,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        collateralAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+
+    console.log("Increase position tx:", tx)
+
+    const positionAcc = await program.account.position.fetch(positionPda)
+    expect(positionAcc.sizeUsd.toString()).toBe(positionBefore.sizeUsd.add(sizeDelta).toString())
+
+    // Added collateral is valued at the oracle min price (spot), less any settled costs
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+    const addedCollateralUsd = custodyAcc.pricing.currentPrice.muln(3).divn(4)
+    expect(positionAcc.collateralUsd.lte(positionBefore.collateralUsd.add(addedCollateralUsd))).toBe(true)
+  })
+
+  it('Decrease Position', async () => {
+    let positionBefore
+    try {
+      positionBefore = await program.account.position.fetch(positionPda)
+    } catch (error) {
+      console.log("Skipping decrease position test - position doesn't exist")
+      return
+    }
+
+    const sizeDelta = positionBefore.sizeUsd.divn(3)
+
+    const tx = await program.methods
+      .decreasePosition(sizeDelta, new anchor.BN(1 * 1_000_000)) // accept down to $1
+      .accountsStrict({
+        owner: user.publicKey,
+        position: positionPda,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        receivingAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+
+    console.log("Decrease position tx:", tx)
+
+    const positionAcc = await program.account.position.fetch(positionPda)
+    expect(positionAcc.sizeUsd.toString()).toBe(positionBefore.sizeUsd.sub(sizeDelta).toString())
+    expect(positionAcc.collateralAmount.lt(positionBefore.collateralAmount)).toBe(true)
+  })
+
+  it('Add Collateral', async () => {
+    let positionBefore
+    try {
+      positionBefore = await program.account.position.fetch(positionPda)
+    } catch (error) {
+      console.log("Skipping add collateral test - position doesn't exist")
+      return
+    }
+
+    const tx = await program.methods
+      .addCollateral(new anchor.BN(0.5 * LAMPORTS_PER_SOL))
+      .accountsStrict({
+        owner: user.publicKey,
+        position: positionPda,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        collateralAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+
+    console.log("Add collateral tx:", tx)
+
+    // More collateral lowers leverage and pushes the long liquidation price down
+    const positionAcc = await program.account.position.fetch(positionPda)
+    expect(positionAcc.leverage.lt(positionBefore.leverage)).toBe(true)
+    expect(positionAcc.liquidationPrice.lt(positionBefore.liquidationPrice)).toBe(true)
+  })
+
+  it('Remove Collateral', async () => {
+    let positionBefore
+    try {
+      positionBefore = await program.account.position.fetch(positionPda)
+    } catch (error) {
+      console.log("Skipping remove collateral test - position doesn't exist")
+      return
+    }
+
+    const tx = await program.methods
+      .removeCollateral(new anchor.BN(0.1 * LAMPORTS_PER_SOL))
+      .accountsStrict({
+        owner: user.publicKey,
+        position: positionPda,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        receivingAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+
+    console.log("Remove collateral tx:", tx)
+
+    const positionAcc = await program.account.position.fetch(positionPda)
+    expect(positionAcc.leverage.gt(positionBefore.leverage)).toBe(true)
+  })
+
+  it('Error: Withdraw PnL without profit', async () => {
+    try {
+      await program.account.position.fetch(positionPda)
+    } catch (error) {
+      console.log("Skipping withdraw pnl test - position doesn't exist")
+      return
+    }
+
+    // Price hasn't moved since entry, so the spread leaves the position at a loss
+    try {
+      await program.methods
+        .withdrawPnl(new anchor.BN(1_000_000))
+        .accountsStrict({
+          owner: user.publicKey,
+          position: positionPda,
+          perpetuals: perpetualsPda,
+          pool: poolPda,
+          custody: custodyPda,
+          mint: mint,
+          custodyTokenAccount: custodyTokenAccount,
+          receivingAccount: userTokenAccount,
+          oracleAccount: user.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID
+        })
+        .signers([user])
+        .rpc()
+
+      throw new Error("Should have failed with insufficient pnl")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toBe("InsufficientPnl")
+    }
+  })
+
+  it('Close Position', async () => {
+    // First check if position exists
+    let positionAcc
+    try {
+      positionAcc = await program.account.position.fetch(positionPda)
+      console.log("Position exists before close:", positionAcc)
+    } catch (error) {
+      console.log("Position doesn't exist, skipping close test")
+      return
+    }
+
+    const oracleAccount = user.publicKey
+
+    // Max out the borrow rate and let interest accrue so the close charges a visible borrow fee
+    const borrowRate = (await program.account.custody.fetch(custodyPda)).borrowRate
+    await setCustodyBorrowRate({ ...borrowRate, baseRate: new anchor.BN(10_000_000) })
+    await new Promise((resolve) => setTimeout(resolve, 2000))
+    const custodyBefore = await program.account.custody.fetch(custodyPda)
+
+    const userBalanceBefore = await getAccount(provider.connection, userTokenAccount)
+    console.log("User token balance before close:", userBalanceBefore.amount.toString())
+
+    try {
+      const tx = await program.methods
+        .closePosition()
+        .accountsStrict({
+          owner: user.publicKey,
+          position: positionPda,
+          positionRegistry: findRegistryPda(user.publicKey),
+          perpetuals: perpetualsPda,
+          pool: poolPda,
+          custody: custodyPda,
+          mint: mint,
+          custodyTokenAccount: custodyTokenAccount,
+          receivingAccount: userTokenAccount, // Added missing receiving account
+          oracleAccount: oracleAccount,
+          tokenProgram: TOKEN_PROGRAM_ID
+        })
+        .signers([user])
+        .rpc()
+
+      console.log("Close position tx:", tx)
+
+      const userBalanceAfter = await getAccount(provider.connection, userTokenAccount)
+      console.log("User token balance after close:", userBalanceAfter.amount.toString())
+
+      // Interest accrued since the position's snapshot is charged on size, in tokens at the exit price
+      const custodyAfter = await program.account.custody.fetch(custodyPda)
+      const interest = custodyAfter.borrowRateState.cumulativeInterest.sub(positionAcc.cumulativeInterestSnapshot)
+      expect(custodyAfter.borrowRateState.cumulativeInterest.gt(custodyBefore.borrowRateState.cumulativeInterest)).toBe(true)
+
+      const pricing = custodyAfter.pricing
+      const minPrice = pricing.useEma ? anchor.BN.min(pricing.currentPrice, pricing.emaPrice) : pricing.currentPrice
+      const exitPrice = minPrice.mul(new anchor.BN(10_000).sub(pricing.tradeSpreadLong)).divn(10_000)
+      const toTokens = (usd: anchor.BN) => usd.mul(new anchor.BN(LAMPORTS_PER_SOL)).div(exitPrice)
+      const protocolShare = (fee: anchor.BN) => fee.mul(custodyAfter.fees.protocolShare).divn(10_000)
+
+      const borrowFee = toTokens(positionAcc.sizeUsd.mul(interest).div(new anchor.BN(1_000_000).mul(new anchor.BN(31_536_000))))
+      const closingFee = toTokens(positionAcc.sizeUsd.mul(custodyAfter.fees.closePosition).divn(10_000))
+      expect(borrowFee.gtn(0)).toBe(true)
+      expect(custodyAfter.assets.protocolFees.sub(custodyBefore.assets.protocolFees).toString())
+        .toBe(protocolShare(closingFee).add(protocolShare(borrowFee)).toString())
+
+      await setCustodyBorrowRate(borrowRate)
+
+      // Position account should be closed
+      try {
+        await program.account.position.fetch(positionPda)
+        console.log("ERROR: Position account should be closed but still exists")
+      } catch (error: any) {
+        console.log("SUCCESS: Position account was properly closed")
+      }
+    } catch (error: any) {
+      console.log("Close position failed:", error.message)
+      console.log("Logs:", error.logs || error.transactionLogs)
+      throw error
+    }
+  })
+
+  it('Place Order', async () => {
+    const collateralAmount = 1 * LAMPORTS_PER_SOL
+    const leverage = 100_000
+
+    // Long limit at $1 stays below the market
+    await placeOrder(0, { open: {} }, 1, collateralAmount, leverage, 1_000_000)
+
+    const orderPda = findOrderPda(user.publicKey, 0)
+    const orderAcc = await program.account.order.fetch(orderPda)
+    expect(orderAcc.owner.toBase58()).toBe(user.publicKey.toBase58())
+    expect(orderAcc.rentDeposit.toNumber()).toBeGreaterThan(0)
+    expect(orderAcc.collateralAmount.toNumber()).toBe(collateralAmount)
+    expect(orderAcc.executionFee.toNumber()).toBe(executionFee)
+
+    // Escrow holds collateral, the opening fee and the keeper fee
+    const escrow = await getAccount(provider.connection, findEscrowPda(orderPda))
+    expect(Number(escrow.amount)).toBeGreaterThan(collateralAmount + executionFee)
+  })
+
+  it('Error: Execute order before trigger', async () => {
+    const keeperTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      authority,
+      mint,
+      authority.publicKey
+    )
+    keeperTokenAccount = keeperTokenAccountInfo.address
+
+    try {
+      await executeOrder(0, 1)
+      throw new Error("Should have failed with order not triggered")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toBe("OrderNotTriggered")
+    }
+  })
+
+  it('Cancel Order', async () => {
+    const orderPda = findOrderPda(user.publicKey, 0)
+    const userBalanceBefore = await getAccount(provider.connection, userTokenAccount)
+    const escrow = await getAccount(provider.connection, findEscrowPda(orderPda))
+
+    await program.methods
+      .cancelOrder()
+      .accountsStrict({
+        owner: user.publicKey,
+        order: orderPda,
+        escrowAccount: findEscrowPda(orderPda),
+        receivingAccount: userTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([user])
+      .rpc()
+
+    const userBalanceAfter = await getAccount(provider.connection, userTokenAccount)
+    expect(userBalanceAfter.amount - userBalanceBefore.amount).toBe(escrow.amount)
+    expect(await provider.connection.getAccountInfo(orderPda)).toBeNull()
+    expect(await provider.connection.getAccountInfo(findEscrowPda(orderPda))).toBeNull()
+  })
+
+  it('Execute Order', async () => {
+    const positionIndex = 1
+    const orderPositionPda = findPositionPda(user.publicKey, 0, positionIndex)
+
+    // Long limit at $1M is already through, the keeper opens the position
+    await placeOrder(1, { open: {} }, positionIndex, 1 * LAMPORTS_PER_SOL, 100_000, 1_000_000 * 1_000_000)
+    const keeperBalanceBefore = await getAccount(provider.connection, keeperTokenAccount)
+    const keeperLamportsBefore = await provider.connection.getBalance(authority.publicKey)
+    await executeOrder(1, positionIndex)
+
+    // Position rent the keeper paid comes back out of the owner's deposit on the order
+    expect(await provider.connection.getBalance(authority.publicKey)).toBe(keeperLamportsBefore)
+
+    const keeperBalanceAfter = await getAccount(provider.connection, keeperTokenAccount)
+    expect(Number(keeperBalanceAfter.amount - keeperBalanceBefore.amount)).toBe(executionFee)
+
+    const positionAcc = await program.account.position.fetch(orderPositionPda)
+    expect(positionAcc.owner.toBase58()).toBe(user.publicKey.toBase58())
+    expect(positionAcc.collateralAmount.toNumber()).toBe(1 * LAMPORTS_PER_SOL)
+    expect(await provider.connection.getAccountInfo(findOrderPda(user.publicKey, 1))).toBeNull()
+
+    // Take profit at $1 is already through as well, the keeper closes it
+    await placeOrder(2, { close: {} }, positionIndex, 0, 0, 1_000_000)
+    await executeOrder(2, positionIndex)
+
+    expect(await provider.connection.getAccountInfo(orderPositionPda)).toBeNull()
+    const registryAcc = await program.account.positionRegistry.fetch(findRegistryPda(user.publicKey))
+    expect(registryAcc.positions.map((p) => p.toBase58())).not.toContain(orderPositionPda.toBase58())
+  })
+
+  it('Stop Loss Order', async () => {
+    const positionIndex = 7
+    const orderPositionPda = findPositionPda(user.publicKey, 0, positionIndex)
+    await openUserPosition(positionIndex, new anchor.BN(1 * LAMPORTS_PER_SOL))
 
-    const positionAcc = await program.account.position.fetch(positionPda)
-    console.log("Position after update:", positionAcc)
+    const orderPositionAcc = await program.account.position.fetch(orderPositionPda)
+    const trigger = 1_000_000 * 1_000_000
+
+    // A take profit at $1M is far above the market and does not fire
+    await placeOrder(3, { close: {} }, positionIndex, 0, 0, trigger)
+    const orderAcc = await program.account.order.fetch(findOrderPda(user.publicKey, 3))
+    expect(orderAcc.positionEntryTimestamp.toString()).toBe(orderPositionAcc.entryTimestamp.toString())
+    try {
+      await executeOrder(3, positionIndex)
+      throw new Error("Should have failed with order not triggered")
+    } catch (error: any) {
+      expect(error.error?.errorCode?.code).toBe("OrderNotTriggered")
+    }
+    await cancelOrder(3)
+
+    // A stop loss at the same trigger is already through for a long, the keeper closes it
+    await placeOrder(4, { stopLoss: {} }, positionIndex, 0, 0, trigger)
+    await executeOrder(4, positionIndex)
+
+    expect(await provider.connection.getAccountInfo(orderPositionPda)).toBeNull()
   })
 
-  it('Close Position', async () => {
-    // First check if position exists
+  it('Error: Close order on a reopened position', async () => {
+    const positionIndex = 8
+    await openUserPosition(positionIndex, new anchor.BN(1 * LAMPORTS_PER_SOL))
+    await placeOrder(5, { close: {} }, positionIndex, 0, 0, 1_000_000)
+
+    // Close by hand and reopen at the same index a few seconds later
+    await closeUserPosition(positionIndex)
+    await new Promise((resolve) => setTimeout(resolve, 2000))
+    await openUserPosition(positionIndex, new anchor.BN(1 * LAMPORTS_PER_SOL))
+
     try {
-      const positionAcc = await program.account.position.fetch(positionPda)
-      console.log("Position exists before close:", positionAcc)
-    } catch (error) {
-      console.log("Position doesn't exist, skipping close test")
-      return
+      await executeOrder(5, positionIndex)
+      throw new Error("Should have failed with an order for a different position")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toBe("OrderPositionMismatch")
     }
 
-    const oracleAccount = user.publicKey
+    await cancelOrder(5)
+    await closeUserPosition(positionIndex)
+  })
 
-    const userBalanceBefore = await getAccount(provider.connection, userTokenAccount)
-    console.log("User token balance before close:", userBalanceBefore.amount.toString())
+  it('Locked liquidity', async () => {
+    const index = 3
+    const positionKey = findPositionPda(user.publicKey, 0, index)
 
+    // Lock all but about 2 tokens of the pool's liquidity
+    const custodyBefore = await program.account.custody.fetch(custodyPda)
+    const collateralAmount = custodyBefore.assets.owned
+      .sub(custodyBefore.assets.locked)
+      .sub(new anchor.BN(2 * LAMPORTS_PER_SOL))
+      .divn(10)
+    await openUserPosition(index, collateralAmount)
+
+    const positionAcc = await program.account.position.fetch(positionKey)
+    const custodyOpen = await program.account.custody.fetch(custodyPda)
+    expect(custodyOpen.assets.locked.sub(custodyBefore.assets.locked).toString())
+      .toBe(positionAcc.lockedAmount.toString())
+
+    // The lock is the position size in tokens at the entry price, the max payout on a long
+    const expectedLocked = positionAcc.sizeUsd.mul(new anchor.BN(LAMPORTS_PER_SOL)).div(positionAcc.entryPrice)
+    expect(positionAcc.lockedAmount.toString()).toBe(expectedLocked.toString())
+
+    // 10% of the LP supply is worth far more than the unlocked liquidity, but less than the custody holds
+    const userLpBalance = await getAccount(provider.connection, userLpTokenAccount)
     try {
-      const tx = await program.methods
-        .closePosition()
+      await program.methods
+        .removeLiquidity(new anchor.BN((userLpBalance.amount / BigInt(10)).toString()), new anchor.BN(0))
         .accountsStrict({
           owner: user.publicKey,
-          position: positionPda,
           perpetuals: perpetualsPda,
           pool: poolPda,
           custody: custodyPda,
-          mint: mint,
+          custodyTokenMint: mint,
+          lpTokenMint: lpTokenMint,
+          lpTokenAccount: userLpTokenAccount,
+          receivingAccount: userTokenAccount,
           custodyTokenAccount: custodyTokenAccount,
-          receivingAccount: userTokenAccount, // Added missing receiving account
-          oracleAccount: oracleAccount,
+          oracleAccount: user.publicKey,
           tokenProgram: TOKEN_PROGRAM_ID
         })
+        .remainingAccounts(poolAccounts([custodyPda, stableCustodyPda]))
         .signers([user])
         .rpc()
+      throw new Error("Should have failed with insufficient liquidity")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toBe("InsufficientLiquidity")
+    }
 
-      console.log("Close position tx:", tx)
+    await closeUserPosition(index)
 
-      const userBalanceAfter = await getAccount(provider.connection, userTokenAccount)
-      console.log("User token balance after close:", userBalanceAfter.amount.toString())
+    const custodyClosed = await program.account.custody.fetch(custodyPda)
+    expect(custodyClosed.assets.locked.toString()).toBe(custodyBefore.assets.locked.toString())
+  })
 
-      // Position account should be closed
-      try {
-        await program.account.position.fetch(positionPda)
-        console.log("ERROR: Position account should be closed but still exists")
-      } catch (error: any) {
-        console.log("SUCCESS: Position account was properly closed")
-      }
-    } catch (error: any) {
-      console.log("Close position failed:", error.message)
-      console.log("Logs:", error.logs || error.transactionLogs)
-      throw error
+  it('Liquidate Position', async () => {
+    const index = 4
+    const positionKey = findPositionPda(user.publicKey, 0, index)
+    await openUserPosition(index, new anchor.BN(1 * LAMPORTS_PER_SOL))
+
+    const positionAcc = await program.account.position.fetch(positionKey)
+    const custodyBefore = await program.account.custody.fetch(custodyPda)
+    const price = custodyBefore.pricing.currentPrice
+
+    // A 5% drop puts the 10x long past its liquidation price without tripping the circuit breaker
+    await setPrice(price.muln(95).divn(100))
+
+    const liquidatorAccount = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      authority,
+      mint,
+      authority.publicKey
+    )
+
+    await program.methods
+      .liquidatePosition()
+      .accountsStrict({
+        liquidator: authority.publicKey,
+        position: positionKey,
+        positionRegistry: findRegistryPda(user.publicKey),
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        liquidatorAccount: liquidatorAccount.address,
+        positionOwnerAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([authority])
+      .rpc()
+
+    const custodyAfter = await program.account.custody.fetch(custodyPda)
+    expect(custodyBefore.assets.locked.sub(custodyAfter.assets.locked).toString())
+      .toBe(positionAcc.lockedAmount.toString())
+    expect(await provider.connection.getAccountInfo(positionKey)).toBeNull()
+
+    await setPrice(price)
+  })
+
+  it('Long, short and second index on one custody', async () => {
+    const long = findPositionPda(user.publicKey, 0, 5)
+    const secondLong = findPositionPda(user.publicKey, 0, 6)
+    const short = findPositionPda(user.publicKey, 1, 5)
+
+    await openUserPosition(5, new anchor.BN(1 * LAMPORTS_PER_SOL))
+    await openUserPosition(6, new anchor.BN(1 * LAMPORTS_PER_SOL))
+    await program.methods
+      .openPosition({ short: {} }, new anchor.BN(5), new anchor.BN(1 * LAMPORTS_PER_SOL), new anchor.BN(100_000), new anchor.BN(1))
+      .accountsStrict({
+        owner: user.publicKey,
+        position: short,
+        positionRegistry: findRegistryPda(user.publicKey),
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        collateralAccount: userTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId
+      })
+      .signers([user])
+      .rpc()
+
+    // Side and index are part of the seeds, so each position gets its own account
+    expect((await program.account.position.fetch(long)).side).toEqual({ long: {} })
+    expect((await program.account.position.fetch(secondLong)).side).toEqual({ long: {} })
+    expect((await program.account.position.fetch(short)).side).toEqual({ short: {} })
+
+    const registryAcc = await program.account.positionRegistry.fetch(findRegistryPda(user.publicKey))
+    const registered = registryAcc.positions.map((p) => p.toBase58())
+    expect(registered).toEqual(expect.arrayContaining([long, secondLong, short].map((p) => p.toBase58())))
+
+    await closeUserPosition(5)
+    await closeUserPosition(6)
+    await closeUserPosition(5, 1)
+
+    for (const position of [long, secondLong, short]) {
+      expect(await provider.connection.getAccountInfo(position)).toBeNull()
     }
   })
 
@@ -367,7 +1744,7 @@ describe('Perpetuals', () => {
         return
       }
 
-      const lpAmountIn = Number(userLpBalance.amount) // Remove all LP tokens
+      const lpAmountIn = Math.floor(Number(userLpBalance.amount) / 10) // Remove 10% of LP tokens
       const minAmountOut = 0
 
       const tx = await program.methods
@@ -382,8 +1759,10 @@ describe('Perpetuals', () => {
           lpTokenAccount: userLpTokenAccount,
           receivingAccount: userTokenAccount, // Use existing user token account
           custodyTokenAccount: custodyTokenAccount,
+          oracleAccount: user.publicKey,
           tokenProgram: TOKEN_PROGRAM_ID
         })
+        .remainingAccounts(poolAccounts([custodyPda, stableCustodyPda]))
         .signers([user])
         .rpc()
 
@@ -404,7 +1783,7 @@ describe('Perpetuals', () => {
   it('Error: Invalid leverage', async () => {
     const side = { long: {} }
     const collateralAmount = 1 * LAMPORTS_PER_SOL
-    const invalidLeverage = 9000 // Exceeds MAX_LEVERAGE of 8000 (80x)
+    const invalidLeverage = 900_000 // Exceeds MAX_LEVERAGE of 800_000 (80x)
     const acceptablePrice = 60 * 1_000_000
     const oracleAccount = user.publicKey
 
@@ -427,15 +1806,13 @@ describe('Perpetuals', () => {
     )
 
     // Find new position PDA for this test (use different seed to avoid conflicts)
-    const [newPositionPda] = PublicKey.findProgramAddressSync(
-      [Buffer.from("position"), authority.publicKey.toBuffer(), poolPda.toBuffer(), custodyPda.toBuffer()],
-      program.programId
-    )
+    const newPositionPda = findPositionPda(authority.publicKey, 0, 0)
 
     try {
       await program.methods
         .openPosition(
           side,
+          new anchor.BN(0),
           new anchor.BN(collateralAmount),
           new anchor.BN(invalidLeverage),
           new anchor.BN(acceptablePrice)
@@ -443,6 +1820,7 @@ describe('Perpetuals', () => {
         .accountsStrict({
           owner: authority.publicKey,
           position: newPositionPda,
+          positionRegistry: findRegistryPda(authority.publicKey),
           perpetuals: perpetualsPda,
           pool: poolPda,
           custody: custodyPda,
@@ -473,7 +1851,7 @@ describe('Perpetuals', () => {
   it('Error: Invalid collateral amount', async () => {
     const side = { long: {} }
     const invalidCollateral = 1000 // Less than MIN_COLLATERAL_SOL (0.01 SOL)
-    const leverage = 10
+    const leverage = 100_000 // 10x
     const acceptablePrice = 60 * 1_000_000
     const oracleAccount = user.publicKey
 
@@ -495,15 +1873,13 @@ describe('Perpetuals', () => {
       10 * LAMPORTS_PER_SOL
     )
 
-    const [newPositionPda] = PublicKey.findProgramAddressSync(
-      [Buffer.from("position"), authority.publicKey.toBuffer(), poolPda.toBuffer(), custodyPda.toBuffer()],
-      program.programId
-    )
+    const newPositionPda = findPositionPda(authority.publicKey, 0, 0)
 
     try {
       await program.methods
         .openPosition(
           side,
+          new anchor.BN(0),
           new anchor.BN(invalidCollateral),
           new anchor.BN(leverage),
           new anchor.BN(acceptablePrice)
@@ -511,6 +1887,7 @@ describe('Perpetuals', () => {
         .accountsStrict({
           owner: authority.publicKey,
           position: newPositionPda,
+          positionRegistry: findRegistryPda(authority.publicKey),
           perpetuals: perpetualsPda,
           pool: poolPda,
           custody: custodyPda,
@@ -531,4 +1908,279 @@ describe('Perpetuals', () => {
       expect(error.error?.errorCode?.code).toContain("InvalidCollateralAmount")
     }
   })
-})
\ No newline at end of file
+
+  it('Error: Leverage above custody max', async () => {
+    // Within MAX_LEVERAGE (80x) but above the custody's 10x max_leverage
+    try {
+      await openAuthorityPosition({ long: {} }, 200_000, 60 * 1_000_000)
+      throw new Error("Should have failed with max leverage exceeded")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toBe("MaxLeverageExceeded")
+    }
+  })
+
+  it('Error: Max global long size exceeded', async () => {
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+    const pricing = custodyAcc.pricing
+
+    // Cap long open interest at $1
+    await setCustodyPricing({ ...pricing, maxGlobalLongSizeUsd: new anchor.BN(1_000_000) })
+
+    try {
+      await openAuthorityPosition({ long: {} }, 100_000, 60 * 1_000_000)
+      throw new Error("Should have failed with max global long size exceeded")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toBe("MaxGlobalLongSizeExceeded")
+    } finally {
+      await setCustodyPricing(pricing)
+    }
+  })
+
+  it('Error: Max global short size exceeded', async () => {
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+    const pricing = custodyAcc.pricing
+
+    // Cap short open interest at $1
+    await setCustodyPricing({ ...pricing, maxGlobalShortSizeUsd: new anchor.BN(1_000_000) })
+
+    try {
+      await openAuthorityPosition({ short: {} }, 100_000, 1)
+      throw new Error("Should have failed with max global short size exceeded")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toBe("MaxGlobalShortSizeExceeded")
+    } finally {
+      await setCustodyPricing(pricing)
+    }
+  })
+
+  it('Set Fee Receiver', async () => {
+    const tx = await program.methods
+      .setFeeReceiver(authority.publicKey)
+      .accountsStrict({
+        authority: authority.publicKey,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+    console.log("Set fee receiver tx:", tx)
+
+    const perpetualsAcc = await program.account.perpetuals.fetch(perpetualsPda)
+    expect(perpetualsAcc.feeReceiver.toBase58()).toBe(authority.publicKey.toBase58())
+  })
+
+  it('Withdraw Fees', async () => {
+    // Generate a fee: a 10 token deposit pays the 0.30% add_liquidity fee, split by the 20% protocol_share
+    const amountIn = new anchor.BN(10 * LAMPORTS_PER_SOL)
+    const fee = amountIn.muln(30).divn(10_000)
+    const protocolFee = fee.muln(2000).divn(10_000)
+    const lpFee = fee.sub(protocolFee)
+
+    const custodyBefore = await program.account.custody.fetch(custodyPda)
+
+    await program.methods
+      .addLiquidity(amountIn, new anchor.BN(0))
+      .accountsStrict({
+        owner: user.publicKey,
+        perpetuals: perpetualsPda,
+        pool: poolPda,
+        custody: custodyPda,
+        custodyTokenMint: mint,
+        lpTokenMint: lpTokenMint,
+        fundingAccount: userTokenAccount,
+        lpTokenAccount: userLpTokenAccount,
+        custodyTokenAccount: custodyTokenAccount,
+        oracleAccount: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .remainingAccounts(poolAccounts([custodyPda, stableCustodyPda]))
+      .signers([user])
+      .rpc()
+
+    const custodyAcc = await program.account.custody.fetch(custodyPda)
+    expect(custodyAcc.assets.protocolFees.sub(custodyBefore.assets.protocolFees).toString())
+      .toBe(protocolFee.toString())
+    expect(custodyAcc.assets.owned.sub(custodyBefore.assets.owned).toString())
+      .toBe(amountIn.sub(fee).add(lpFee).toString())
+
+    const protocolFees = custodyAcc.assets.protocolFees
+
+    const treasuryAccountInfo = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      authority,
+      mint,
+      authority.publicKey
+    )
+    const treasuryBefore = await getAccount(provider.connection, treasuryAccountInfo.address)
+    const custodyTokensBefore = await getAccount(provider.connection, custodyTokenAccount)
+
+    const tx = await program.methods
+      .withdrawFees(protocolFees)
+      .accountsStrict({
+        authority: authority.publicKey,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda,
+        pool: poolPda,
+        custody: custodyPda,
+        mint: mint,
+        custodyTokenAccount: custodyTokenAccount,
+        receivingAccount: treasuryAccountInfo.address,
+        tokenProgram: TOKEN_PROGRAM_ID
+      })
+      .signers([authority])
+      .rpc()
+
+    console.log("Withdraw fees tx:", tx)
+
+    // Only the protocol share leaves the custody, the LP share stays in owned
+    const updatedCustody = await program.account.custody.fetch(custodyPda)
+    expect(updatedCustody.assets.protocolFees.toNumber()).toBe(0)
+    expect(updatedCustody.assets.owned.toString()).toBe(custodyAcc.assets.owned.toString())
+
+    const treasuryAfter = await getAccount(provider.connection, treasuryAccountInfo.address)
+    const custodyTokensAfter = await getAccount(provider.connection, custodyTokenAccount)
+    expect((treasuryAfter.amount - treasuryBefore.amount).toString()).toBe(protocolFees.toString())
+    expect((custodyTokensBefore.amount - custodyTokensAfter.amount).toString()).toBe(protocolFees.toString())
+  })
+
+  it('Set Custody Permissions', async () => {
+    const permissions = {
+      allowSwap: true,
+      allowAddLiquidity: true,
+      allowRemoveLiquidity: true,
+      allowOpenPosition: false, // halt new positions on this market only
+      allowClosePosition: true,
+      allowPnlWithdrawal: true,
+      allowCollateralWithdrawal: true,
+      allowSizeChange: true,
+    }
+
+    const tx = await program.methods
+      .setCustodyPermissions(permissions)
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: custodyPda,
+        pool: poolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+    console.log("Set custody permissions tx:", tx)
+
+    let custodyAcc = await program.account.custody.fetch(custodyPda)
+    expect(custodyAcc.permissions?.allowOpenPosition).toBe(false)
+
+    // Clear the override so the custody follows the global permissions again
+    await program.methods
+      .setCustodyPermissions(null)
+      .accountsStrict({
+        authority: authority.publicKey,
+        custody: custodyPda,
+        pool: poolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+    custodyAcc = await program.account.custody.fetch(custodyPda)
+    expect(custodyAcc.permissions).toBeNull()
+  })
+
+  it('Error: Non-admin cannot sign admin instructions', async () => {
+    try {
+      await program.methods
+        .updatePrice(new anchor.BN(1_000_000))
+        .accountsStrict({
+          authority: user.publicKey,
+          custody: custodyPda,
+          pool: poolPda,
+          mint: mint,
+          perpetuals: perpetualsPda,
+          multisig: multisigPda
+        })
+        .signers([user])
+        .rpc()
+
+      throw new Error("Should have failed with unauthorized signer")
+    } catch (error: any) {
+      console.log("Caught expected error:", error.error?.errorCode?.code || error.message)
+      expect(error.error?.errorCode?.code).toContain("MultisigAccountNotAuthorized")
+    }
+  })
+
+  it('Set Admin Signers', async () => {
+    const newAdmins = [authority.publicKey, user.publicKey]
+
+    const tx = await program.methods
+      .setAdminSigners(newAdmins, 1)
+      .accountsStrict({
+        authority: authority.publicKey,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+    console.log("Set admin signers tx:", tx)
+
+    const perpetualsAcc = await program.account.perpetuals.fetch(perpetualsPda)
+    expect(perpetualsAcc.admins.length).toBe(2)
+    expect(perpetualsAcc.minSignatures).toBe(1)
+  })
+
+  it('Multisig requires min signatures', async () => {
+    await program.methods
+      .setAdminSigners([authority.publicKey, user.publicKey], 2)
+      .accountsStrict({
+        authority: authority.publicKey,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([authority])
+      .rpc()
+
+    let perpetualsAcc = await program.account.perpetuals.fetch(perpetualsPda)
+    expect(perpetualsAcc.minSignatures).toBe(2)
+
+    const priceBefore = (await program.account.custody.fetch(custodyPda)).pricing.currentPrice
+    const newPrice = priceBefore.muln(101).divn(100)
+
+    const updatePrice = (signer: Keypair) => program.methods
+      .updatePrice(newPrice)
+      .accountsStrict({
+        authority: signer.publicKey,
+        custody: custodyPda,
+        pool: poolPda,
+        mint: mint,
+        perpetuals: perpetualsPda,
+        multisig: multisigPda
+      })
+      .signers([signer])
+      .rpc()
+
+    // First signature only records the approval
+    await updatePrice(authority)
+
+    let custodyAcc = await program.account.custody.fetch(custodyPda)
+    expect(custodyAcc.pricing.currentPrice.toString()).toBe(priceBefore.toString())
+    let multisigAcc = await program.account.multisig.fetch(multisigPda)
+    expect(multisigAcc.signers.length).toBe(1)
+
+    // Second signature reaches min_signatures and executes the update
+    await updatePrice(user)
+
+    custodyAcc = await program.account.custody.fetch(custodyPda)
+    expect(custodyAcc.pricing.currentPrice.toString()).toBe(newPrice.toString())
+    multisigAcc = await program.account.multisig.fetch(multisigPda)
+    expect(multisigAcc.signers.length).toBe(0)
+  })
+})
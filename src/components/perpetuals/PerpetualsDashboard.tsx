@@ -43,7 +43,9 @@ interface AddCustodyArgs {
   poolName: string;
   mint: PublicKey;
   isStable: boolean;
+  oracle: PublicKey;
   oracleType: { none: {} } | { pyth: {} } | { custom: {} };
+  feedId: string | null;
   initialPrice: number;
   authorityPubkey: PublicKey;
 }
@@ -69,6 +71,7 @@ interface OpenPositionArgs {
   poolName: string;
   mint: PublicKey;
   side: { long: {} } | { short: {} };
+  index: number;
   collateralAmount: number;
   leverage: number;
   acceptablePrice: number;
@@ -264,6 +267,8 @@ const AddCustodyForm = ({ onSubmit, isLoading, pools }: { onSubmit: (data: AddCu
   const [mintAddress, setMintAddress] = useState('');
   const [isStable, setIsStable] = useState('false');
   const [oracleType, setOracleType] = useState('none');
+  const [oracleAddress, setOracleAddress] = useState('');
+  const [feedId, setFeedId] = useState('');
   const [initialPrice, setInitialPrice] = useState('1000000');
   const [authorityAddress, setAuthorityAddress] = useState('');
 
@@ -277,6 +282,7 @@ const AddCustodyForm = ({ onSubmit, isLoading, pools }: { onSubmit: (data: AddCu
     try {
       const mint = new PublicKey(mintAddress);
       const authorityPubkey = new PublicKey(authorityAddress);
+      const oracle = oracleAddress ? new PublicKey(oracleAddress) : PublicKey.default;
       
       let oracleTypeObj;
       switch (oracleType) {
@@ -294,7 +300,9 @@ const AddCustodyForm = ({ onSubmit, isLoading, pools }: { onSubmit: (data: AddCu
         poolName,
         mint,
         isStable: isStable === 'true',
+        oracle,
         oracleType: oracleTypeObj,
+        feedId: feedId || null,
         initialPrice: parseInt(initialPrice),
         authorityPubkey
       });
@@ -336,6 +344,18 @@ const AddCustodyForm = ({ onSubmit, isLoading, pools }: { onSubmit: (data: AddCu
           { value: 'custom', label: 'Custom' }
         ]}
       />
+      <Input
+        label="Oracle Address"
+        value={oracleAddress}
+        onChange={setOracleAddress}
+        placeholder="Pyth price update or custom oracle account"
+      />
+      <Input
+        label="Feed ID"
+        value={feedId}
+        onChange={setFeedId}
+        placeholder="Pyth feed id (hex), Pyth only"
+      />
       <Input
         label="Initial Price (in micro units)"
         value={initialPrice}
@@ -516,6 +536,7 @@ const AddLiquidityForm = ({ onSubmit, isLoading, custodies }: { onSubmit: (data:
 const OpenPositionForm = ({ onSubmit, isLoading, custodies }: { onSubmit: (data: OpenPositionArgs) => void, isLoading: boolean, custodies: any[] }) => {
   const [selectedCustody, setSelectedCustody] = useState('');
   const [side, setSide] = useState('long');
+  const [positionIndex, setPositionIndex] = useState('0');
   const [collateralAmount, setCollateralAmount] = useState('');
   const [leverage, setLeverage] = useState('2');
   const [acceptablePrice, setAcceptablePrice] = useState('');
@@ -549,8 +570,9 @@ const OpenPositionForm = ({ onSubmit, isLoading, custodies }: { onSubmit: (data:
         poolName: custodyData.poolName,
         mint,
         side: side === 'long' ? { long: {} } : { short: {} },
+        index: parseInt(positionIndex),
         collateralAmount: parseFloat(collateralAmount) * 1e6,
-        leverage: parseFloat(leverage) * 100, // Convert to basis points
+        leverage: Math.round(parseFloat(leverage) * 10_000), // Convert to basis points, 10_000 = 1x
         acceptablePrice: parseFloat(acceptablePrice) * 1e6,
         ownerPubkey: publicKey,
         collateralAccount: collateralAccountPubkey,
@@ -578,6 +600,13 @@ const OpenPositionForm = ({ onSubmit, isLoading, custodies }: { onSubmit: (data:
           { value: 'short', label: 'Short (Sell)' }
         ]}
       />
+      <Input
+        label="Position Index"
+        value={positionIndex}
+        onChange={setPositionIndex}
+        type="number"
+        placeholder="0"
+      />
       <Input
         label="Collateral Amount"
         value={collateralAmount}
@@ -1339,7 +1368,7 @@ const PositionCard = ({ position }: { position: any }) => {
         <div>
           <p className="text-sm text-gray-600">Leverage</p>
           <p className="text-lg font-medium text-gray-900">
-            {positionAccount?.leverage ? `${Number(positionAccount.leverage) / 100}x` : 'N/A'}
+            {positionAccount?.leverage ? `${Number(positionAccount.leverage) / 10_000}x` : 'N/A'}
           </p>
         </div>
       </div>
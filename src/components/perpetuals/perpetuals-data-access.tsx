@@ -30,7 +30,9 @@ interface AddCustodyArgs {
   poolName: string
   mint: PublicKey
   isStable: boolean
+  oracle: PublicKey
   oracleType: { none: {} } | { pyth: {} } | { custom: {} }
+  feedId: string | null
   initialPrice: number
   authorityPubkey: PublicKey
 }
@@ -66,6 +68,7 @@ interface OpenPositionArgs {
   poolName: string
   mint: PublicKey
   side: { long: {} } | { short: {} }
+  index: number
   collateralAmount: number
   leverage: number
   acceptablePrice: number
@@ -77,6 +80,8 @@ interface OpenPositionArgs {
 interface ClosePositionArgs {
   poolName: string
   mint: PublicKey
+  side: { long: {} } | { short: {} }
+  index: number
   ownerPubkey: PublicKey
   receivingAccount: PublicKey
   oracleAccount: PublicKey
@@ -85,6 +90,8 @@ interface ClosePositionArgs {
 interface LiquidatePositionArgs {
   poolName: string
   mint: PublicKey
+  side: { long: {} } | { short: {} }
+  index: number
   positionOwner: PublicKey
   liquidatorPubkey: PublicKey
   liquidatorAccount: PublicKey
@@ -95,6 +102,8 @@ interface LiquidatePositionArgs {
 interface UpdatePositionArgs {
   poolName: string
   mint: PublicKey
+  side: { long: {} } | { short: {} }
+  index: number
   positionOwner: PublicKey
   oracleAccount: PublicKey
 }
@@ -133,6 +142,36 @@ export function usePerpetualsProgram() {
     queryFn: () => connection.getParsedAccountInfo(programId),
   })
 
+  // Position seeds: owner, pool, custody, side (0 long, 1 short), index as u64 LE
+  const findPositionPda = (owner: PublicKey, poolPda: PublicKey, custodyPda: PublicKey, side: { long: {} } | { short: {} }, index: number) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        owner.toBuffer(),
+        poolPda.toBuffer(),
+        custodyPda.toBuffer(),
+        Buffer.from(['long' in side ? 0 : 1]),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 8)
+      ],
+      program.programId
+    )[0]
+
+  const findPositionRegistryPda = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("position_registry"), owner.toBuffer()],
+      program.programId
+    )[0]
+
+  // remaining_accounts for pool valuation: every custody in the pool, followed by their oracle accounts
+  const getPoolValueAccounts = async (poolPda: PublicKey) => {
+    const pool = await program.account.pool.fetch(poolPda)
+    const custodies = await program.account.custody.fetchMultiple(pool.custodies)
+    return [
+      ...pool.custodies.map((pubkey) => ({ pubkey, isSigner: false, isWritable: false })),
+      ...custodies.map((custody) => ({ pubkey: custody!.oracle, isSigner: false, isWritable: false })),
+    ]
+  }
+
   // ADMIN ONLY: Initialize the perpetuals program
   const initialize = useMutation<string, Error, InitializeArgs>({
     mutationKey: ['perpetuals', 'initialize', { cluster }],
@@ -142,11 +181,17 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
+      const [multisigPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("multisig")],
+        program.programId
+      )
+
       return await program.methods
         .initialize(minSignatures, admins)
         .accountsStrict({ 
           admin: adminPubkey,
           perpetuals: perpetualsPda,
+          multisig: multisigPda,
           systemProgram: SystemProgram.programId
         })
         .rpc()
@@ -169,6 +214,11 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
+      const [multisigPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("multisig")],
+        program.programId
+      )
+
       const [poolPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("pool"), Buffer.from(poolName)],
         program.programId
@@ -186,6 +236,7 @@ export function usePerpetualsProgram() {
           pool: poolPda,
           lpTokenMint: lpTokenMint,
           perpetuals: perpetualsPda,
+          multisig: multisigPda,
           tokenProgram: TOKEN_PROGRAM_ID,
           systemProgram: SystemProgram.programId
         })
@@ -204,12 +255,17 @@ export function usePerpetualsProgram() {
   // ADMIN ONLY: Add custody (token) to a pool
   const addCustody = useMutation<string, Error, AddCustodyArgs>({
     mutationKey: ['perpetuals', 'add-custody', { cluster }],
-    mutationFn: async({ poolName, mint, isStable, oracleType, initialPrice, authorityPubkey }) => {
+    mutationFn: async({ poolName, mint, isStable, oracle, oracleType, feedId, initialPrice, authorityPubkey }) => {
       const [perpetualsPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("perpetuals")],
         program.programId
       )
 
+      const [multisigPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("multisig")],
+        program.programId
+      )
+
       const [poolPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("pool"), Buffer.from(poolName)],
         program.programId
@@ -226,13 +282,14 @@ export function usePerpetualsProgram() {
       )
 
       return await program.methods
-        .addCustody(isStable, oracleType, new anchor.BN(initialPrice))
+        .addCustody(isStable, oracle, oracleType, feedId, new anchor.BN(initialPrice))
         .accountsStrict({
           authority: authorityPubkey,
           custody: custodyPda,
           custodyTokenMint: mint,
           pool: poolPda,
           perpetuals: perpetualsPda,
+          multisig: multisigPda,
           custodyTokenAccount: custodyTokenAccount,
           systemProgram: SystemProgram.programId,
           tokenProgram: TOKEN_PROGRAM_ID
@@ -258,6 +315,11 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
+      const [multisigPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("multisig")],
+        program.programId
+      )
+
       const [poolPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("pool"), Buffer.from(poolName)],
         program.programId
@@ -275,7 +337,8 @@ export function usePerpetualsProgram() {
           custody: custodyPda,
           pool: poolPda,
           mint: mint,
-          perpetuals: perpetualsPda
+          perpetuals: perpetualsPda,
+          multisig: multisigPda
         })
         .rpc()
     },
@@ -317,6 +380,9 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
+      const custody = await program.account.custody.fetch(custodyPda)
+      const poolValueAccounts = await getPoolValueAccounts(poolPda)
+
       return await program.methods
         .addLiquidity(new anchor.BN(amountIn), new anchor.BN(minLpAmountOut))
         .accountsStrict({
@@ -329,8 +395,10 @@ export function usePerpetualsProgram() {
           fundingAccount: fundingAccount,
           lpTokenAccount: lpTokenAccount,
           custodyTokenAccount: custodyTokenAccount,
+          oracleAccount: custody.oracle,
           tokenProgram: TOKEN_PROGRAM_ID
         })
+        .remainingAccounts(poolValueAccounts)
         .rpc()
     },
     onSuccess: async (signature) => {
@@ -371,6 +439,9 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
+      const custody = await program.account.custody.fetch(custodyPda)
+      const poolValueAccounts = await getPoolValueAccounts(poolPda)
+
       return await program.methods
         .removeLiquidity(new anchor.BN(lpAmountIn), new anchor.BN(minAmountOut))
         .accountsStrict({
@@ -383,8 +454,10 @@ export function usePerpetualsProgram() {
           lpTokenAccount: lpTokenAccount,
           receivingAccount: receivingAccount,
           custodyTokenAccount: custodyTokenAccount,
+          oracleAccount: custody.oracle,
           tokenProgram: TOKEN_PROGRAM_ID
         })
+        .remainingAccounts(poolValueAccounts)
         .rpc()
     },
     onSuccess: async (signature) => {
@@ -399,7 +472,7 @@ export function usePerpetualsProgram() {
   // PUBLIC: Open a new position
   const openPosition = useMutation<string, Error, OpenPositionArgs>({
     mutationKey: ['perpetuals', 'open-position', { cluster }],
-    mutationFn: async({ poolName, mint, side, collateralAmount, leverage, acceptablePrice, ownerPubkey, collateralAccount, oracleAccount }) => {
+    mutationFn: async({ poolName, mint, side, index, collateralAmount, leverage, acceptablePrice, ownerPubkey, collateralAccount, oracleAccount }) => {
       const [perpetualsPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("perpetuals")],
         program.programId
@@ -415,10 +488,7 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
-      const [positionPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("position"), ownerPubkey.toBuffer(), poolPda.toBuffer(), custodyPda.toBuffer()],
-        program.programId
-      )
+      const positionPda = findPositionPda(ownerPubkey, poolPda, custodyPda, side, index)
 
       const [custodyTokenAccount] = PublicKey.findProgramAddressSync(
         [Buffer.from("custody_token_account"), poolPda.toBuffer(), mint.toBuffer()],
@@ -428,6 +498,7 @@ export function usePerpetualsProgram() {
       return await program.methods
         .openPosition(
           side,
+          new anchor.BN(index),
           new anchor.BN(collateralAmount),
           new anchor.BN(leverage),
           new anchor.BN(acceptablePrice)
@@ -435,6 +506,7 @@ export function usePerpetualsProgram() {
         .accountsStrict({
           owner: ownerPubkey,
           position: positionPda,
+          positionRegistry: findPositionRegistryPda(ownerPubkey),
           perpetuals: perpetualsPda,
           pool: poolPda,
           custody: custodyPda,
@@ -460,7 +532,7 @@ export function usePerpetualsProgram() {
   // PUBLIC: Close a position
   const closePosition = useMutation<string, Error, ClosePositionArgs>({
     mutationKey: ['perpetuals', 'close-position', { cluster }],
-    mutationFn: async({ poolName, mint, ownerPubkey, receivingAccount, oracleAccount }) => {
+    mutationFn: async({ poolName, mint, side, index, ownerPubkey, receivingAccount, oracleAccount }) => {
       const [perpetualsPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("perpetuals")],
         program.programId
@@ -476,10 +548,7 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
-      const [positionPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("position"), ownerPubkey.toBuffer(), poolPda.toBuffer(), custodyPda.toBuffer()],
-        program.programId
-      )
+      const positionPda = findPositionPda(ownerPubkey, poolPda, custodyPda, side, index)
 
       const [custodyTokenAccount] = PublicKey.findProgramAddressSync(
         [Buffer.from("custody_token_account"), poolPda.toBuffer(), mint.toBuffer()],
@@ -491,6 +560,7 @@ export function usePerpetualsProgram() {
         .accountsStrict({
           owner: ownerPubkey,
           position: positionPda,
+          positionRegistry: findPositionRegistryPda(ownerPubkey),
           perpetuals: perpetualsPda,
           pool: poolPda,
           custody: custodyPda,
@@ -515,7 +585,7 @@ export function usePerpetualsProgram() {
   // PUBLIC: Liquidate an undercollateralized position
   const liquidatePosition = useMutation<string, Error, LiquidatePositionArgs>({
     mutationKey: ['perpetuals', 'liquidate-position', { cluster }],
-    mutationFn: async({ poolName, mint, positionOwner, liquidatorPubkey, liquidatorAccount, positionOwnerAccount, oracleAccount }) => {
+    mutationFn: async({ poolName, mint, side, index, positionOwner, liquidatorPubkey, liquidatorAccount, positionOwnerAccount, oracleAccount }) => {
       const [poolPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("pool"), Buffer.from(poolName)],
         program.programId
@@ -526,10 +596,7 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
-      const [positionPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("position"), positionOwner.toBuffer(), poolPda.toBuffer(), custodyPda.toBuffer()],
-        program.programId
-      )
+      const positionPda = findPositionPda(positionOwner, poolPda, custodyPda, side, index)
 
       const [custodyTokenAccount] = PublicKey.findProgramAddressSync(
         [Buffer.from("custody_token_account"), poolPda.toBuffer(), mint.toBuffer()],
@@ -541,6 +608,7 @@ export function usePerpetualsProgram() {
         .accountsStrict({
           liquidator: liquidatorPubkey,
           position: positionPda,
+          positionRegistry: findPositionRegistryPda(positionOwner),
           pool: poolPda,
           custody: custodyPda,
           mint: mint,
@@ -565,7 +633,7 @@ export function usePerpetualsProgram() {
   // PUBLIC: Update position PnL
   const updatePosition = useMutation<string, Error, UpdatePositionArgs>({
     mutationKey: ['perpetuals', 'update-position', { cluster }],
-    mutationFn: async({ poolName, mint, positionOwner, oracleAccount }) => {
+    mutationFn: async({ poolName, mint, side, index, positionOwner, oracleAccount }) => {
       const [poolPda] = PublicKey.findProgramAddressSync(
         [Buffer.from("pool"), Buffer.from(poolName)],
         program.programId
@@ -576,10 +644,7 @@ export function usePerpetualsProgram() {
         program.programId
       )
 
-      const [positionPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from("position"), positionOwner.toBuffer(), poolPda.toBuffer(), custodyPda.toBuffer()],
-        program.programId
-      )
+      const positionPda = findPositionPda(positionOwner, poolPda, custodyPda, side, index)
 
       return await program.methods
         .updatePosition()
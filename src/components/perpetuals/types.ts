@@ -84,7 +84,6 @@ export interface TradeStats {
 
 // Account types matching the Rust program
 export interface PerpetualsAccount {
-  adminAuthority: PublicKey;
   minSignatures: number;
   admins: PublicKey[];
   pools: PublicKey[];